@@ -0,0 +1,116 @@
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+const ZIP_MAGIC: [u8; 4] = [0x50, 0x4B, 0x03, 0x04];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ROM_EXTENSIONS: [&str; 2] = [".gb", ".gbc"];
+// the largest cartridges, MBC5, have 8MB of ROM
+const MAX_ROM_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Decompresses zip and gzip archives in memory, other data is returned unchanged.
+/// From a zip archive the entry with the given name is picked, or the first GB/GBC ROM.
+pub fn extract_rom(data: Vec<u8>, entry_name: Option<&str>) -> io::Result<Vec<u8>> {
+    if data.starts_with(&ZIP_MAGIC) {
+        extract_zip(data, entry_name)
+    } else if data.starts_with(&GZIP_MAGIC) {
+        read_limited(GzDecoder::new(&data[..]))
+    } else {
+        Ok(data)
+    }
+}
+
+fn extract_zip(data: Vec<u8>, entry_name: Option<&str>) -> io::Result<Vec<u8>> {
+    let mut zip = ZipArchive::new(Cursor::new(data)).map_err(to_io_error)?;
+    let idx = (0..zip.len())
+        .find(|&i| match zip.by_index(i) {
+            Ok(f) => match entry_name {
+                Some(name) => f.name() == name,
+                None => is_rom_name(f.name()),
+            },
+            Err(_) => false,
+        })
+        .ok_or_else(|| {
+            let msg = match entry_name {
+                Some(name) => format!("no entry named {} in the archive", name),
+                None => "no .gb or .gbc file in the archive".to_string(),
+            };
+            io::Error::new(io::ErrorKind::NotFound, msg)
+        })?;
+    let f = zip.by_index(idx).map_err(to_io_error)?;
+    read_limited(f)
+}
+
+/// Reads a decompressed ROM, failing rather than filling memory on a larger stream
+fn read_limited(reader: impl Read) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    reader.take(MAX_ROM_SIZE + 1).read_to_end(&mut out)?;
+    if out.len() as u64 > MAX_ROM_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed ROM is larger than 8MB"));
+    }
+    Ok(out)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    ROM_EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+}
+
+fn to_io_error(e: zip::result::ZipError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn gzip_test() {
+        let rom = vec![1, 2, 3, 4];
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&rom).unwrap();
+        let gz = encoder.finish().unwrap();
+        assert_eq!(extract_rom(gz, None).unwrap(), rom);
+    }
+
+    #[test]
+    fn gzip_size_limit_test() {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&vec![0; MAX_ROM_SIZE as usize + 1]).unwrap();
+        let gz = encoder.finish().unwrap();
+        let e = extract_rom(gz, None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn zip_test() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, data) in [("readme.txt", [0]), ("a.gb", [1]), ("b.gbc", [2])].iter() {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        let zip = writer.finish().unwrap().into_inner();
+        assert_eq!(extract_rom(zip.clone(), None).unwrap(), vec![1]);
+        assert_eq!(extract_rom(zip.clone(), Some("b.gbc")).unwrap(), vec![2]);
+        assert!(extract_rom(zip, Some("c.gb")).is_err());
+    }
+
+    #[test]
+    fn uncompressed_test() {
+        let rom = vec![0x00, 0xC3, 0x50, 0x01];
+        assert_eq!(extract_rom(rom.clone(), None).unwrap(), rom);
+    }
+
+    #[test]
+    fn rom_name_test() {
+        assert!(is_rom_name("Tetris.GB"));
+        assert!(is_rom_name("dir/game.gbc"));
+        assert!(!is_rom_name("readme.txt"));
+    }
+}
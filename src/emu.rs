@@ -1,5 +1,10 @@
+use crate::error::EmuError;
 use crate::gb::*;
 use crate::gb::cpu::Cpu;
+use crate::gb::ppu::Renderer;
+use crate::gb::ram_search::Filter as SearchFilter;
+use crate::gb::ram_search::RamSearch;
+use crate::gb::ram_search::Width;
 use crate::gb::joypad::JoypadInterrupt;
 use piston_window::*;
 use crate::frontend::*;
@@ -10,24 +15,20 @@ pub struct Emu {
 }
 
 impl Emu {
-    pub fn run_loop(&mut self, skip_bootrom: bool) {
+    pub fn run_loop(&mut self) -> Result<(), EmuError> {
         let gb = &mut self.gb;
         let mut breakpoints: Vec<u16> = vec![];
+        let mut ram_search = RamSearch::new();
         let mut is_debug = false;
 
-        if skip_bootrom {
-            gb.cpu.pc = 0x100;
-            init_io_registers(&mut gb.cpu);
-        }
-
         let mut last_frame_nanos = std::time::Instant::now();
         loop {
-            let should_redraw = gb.run_machine_cycle(is_debug);
+            let should_redraw = gb.step()?;
             if breakpoints.contains(&gb.cpu.pc) {
                 is_debug = true;
             }
-            if is_debug && !gb.cpu.is_busy() {
-                is_debug = do_debug_stuff(&gb.cpu, &mut breakpoints);
+            if is_debug {
+                is_debug = do_debug_stuff(&mut gb.cpu, &mut breakpoints, &mut ram_search);
             }
 
             if should_redraw {
@@ -39,6 +40,9 @@ impl Emu {
                     std::thread::sleep(sleep_dur);
                 }
                 self.frontend.render(gb);
+                if self.frontend.closed() {
+                    return Ok(());
+                }
 
                 last_frame_nanos = std::time::Instant::now();
             }
@@ -46,12 +50,24 @@ impl Emu {
     }
 }
 
-fn do_debug_stuff(cpu: &Cpu, breakpoints: &mut Vec<u16>) -> bool {
+fn do_debug_stuff(cpu: &mut Cpu, breakpoints: &mut Vec<u16>, ram_search: &mut RamSearch) -> bool {
     print_registers(&cpu);
     let mut line = String::new();
     std::io::stdin().read_line(&mut line).unwrap();
     let continue_debugging = match line.trim() {
         "q" => false,
+        l if l.starts_with("cheat") => {
+            do_cheat_command(cpu, l);
+            true
+        }
+        l if l.starts_with("search") => {
+            do_search_command(cpu, ram_search, l);
+            true
+        }
+        l if l.starts_with("renderer") => {
+            do_renderer_command(cpu, l);
+            true
+        }
         l => {
             if let Ok(addr) = u16::from_str_radix(l, 16) {
                 *breakpoints = vec![addr];
@@ -64,6 +80,84 @@ fn do_debug_stuff(cpu: &Cpu, breakpoints: &mut Vec<u16>) -> bool {
     continue_debugging
 }
 
+// cheats               - list cheats
+// cheat <idx>          - toggle cheat
+// cheat add <code> ... - add a new cheat
+fn do_cheat_command(cpu: &mut Cpu, line: &str) {
+    let cheats = &mut cpu.mmu.cheats;
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.as_slice() {
+        ["cheats"] => {
+            for (idx, cheat) in cheats.list.iter().enumerate() {
+                let state = if cheat.enabled { "on" } else { "off" };
+                eprintln!("{:3} [{:3}] {:12} {}", idx, state, cheat.code, cheat.name);
+            }
+        }
+        ["cheat", "add", code, name @ ..] => {
+            if !cheats.add(code, &name.join(" "), true) {
+                eprintln!("Invalid cheat code: {}", code);
+            }
+        }
+        ["cheat", idx] => {
+            match idx.parse().ok().and_then(|idx| cheats.toggle(idx)) {
+                Some(true) => eprintln!("Cheat {} enabled", idx),
+                Some(false) => eprintln!("Cheat {} disabled", idx),
+                None => eprintln!("No such cheat: {}", idx),
+            }
+        }
+        _ => eprintln!("Unknown command: {}", line),
+    }
+}
+
+// renderer scanline|fifo - switch the PPU renderer, from the next line on
+fn do_renderer_command(cpu: &mut Cpu, line: &str) {
+    let name = line.split_whitespace().nth(1).unwrap_or("");
+    match Renderer::from_name(name) {
+        Some(renderer) => cpu.mmu.ppu.renderer = renderer,
+        None => eprintln!("Unknown renderer: {}", name),
+    }
+}
+
+// search new [8|16]    - snapshot RAM, all addresses become candidates
+// search eq|ne|inc|dec - compare candidates with the previous snapshot
+// search <value>       - keep candidates equal to the value (decimal or 0x-prefixed hex)
+// search list          - print candidates
+fn do_search_command(cpu: &Cpu, ram_search: &mut RamSearch, line: &str) {
+    const MAX_LISTED: usize = 32;
+    let mmu = &cpu.mmu;
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.as_slice() {
+        ["search", "new"] | ["search", "new", "8"] => ram_search.reset(mmu, Width::Byte),
+        ["search", "new", "16"] => ram_search.reset(mmu, Width::Word),
+        ["search", "eq"] => ram_search.filter(mmu, SearchFilter::Equal),
+        ["search", "ne"] => ram_search.filter(mmu, SearchFilter::Changed),
+        ["search", "inc"] => ram_search.filter(mmu, SearchFilter::Increased),
+        ["search", "dec"] => ram_search.filter(mmu, SearchFilter::Decreased),
+        ["search", "list"] => {
+            for c in ram_search.candidates().iter().take(MAX_LISTED) {
+                eprintln!("{:04X}: {:X}", c.addr, c.prev);
+            }
+        }
+        ["search", val] => match parse_number(val) {
+            Some(n) if n > ram_search.width().max_value() => {
+                eprintln!("{} doesn't fit in a byte, use \"search new 16\" to search words", val);
+                return;
+            }
+            Some(n) => ram_search.filter(mmu, SearchFilter::Value(n)),
+            None => eprintln!("Invalid value: {}", val),
+        },
+        _ => eprintln!("Unknown command: {}", line),
+    }
+    eprintln!("{} candidates left", ram_search.candidates().len());
+}
+
+fn parse_number(s: &str) -> Option<u16> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
 fn print_registers(cpu: &Cpu) {
     eprintln!();
     eprintln!("af: {:02X}{:02X} ", cpu.a, cpu.f);
@@ -109,36 +203,3 @@ fn print_io_registers(cpu: &Cpu) {
     eprintln!("[$FF49] = {:02x} ($FF) ; OBP1", cpu.mmu.read_byte(0xFF49));
     eprintln!("[$FF4A] = {:02x} ($00) ; W   ", cpu.mmu.read_byte(0xFF4A));
 }
-
-fn init_io_registers(cpu: &mut Cpu) {
-    cpu.mmu.write_byte(0x00, 0xFF05);
-    cpu.mmu.write_byte(0x00, 0xFF06);
-    cpu.mmu.write_byte(0x00, 0xFF07);
-    cpu.mmu.write_byte(0x80, 0xFF10);
-    cpu.mmu.write_byte(0xBF, 0xFF11);
-    cpu.mmu.write_byte(0xF3, 0xFF12);
-    cpu.mmu.write_byte(0xBF, 0xFF14);
-    cpu.mmu.write_byte(0x3F, 0xFF16);
-    cpu.mmu.write_byte(0x00, 0xFF17);
-    cpu.mmu.write_byte(0xBF, 0xFF19);
-    cpu.mmu.write_byte(0x7F, 0xFF1A);
-    cpu.mmu.write_byte(0xFF, 0xFF1B);
-    cpu.mmu.write_byte(0x9F, 0xFF1C);
-    cpu.mmu.write_byte(0xBF, 0xFF1E);
-    cpu.mmu.write_byte(0xFF, 0xFF20);
-    cpu.mmu.write_byte(0x00, 0xFF21);
-    cpu.mmu.write_byte(0x00, 0xFF22);
-    cpu.mmu.write_byte(0xBF, 0xFF23);
-    cpu.mmu.write_byte(0x77, 0xFF24);
-    cpu.mmu.write_byte(0xF3, 0xFF25);
-    cpu.mmu.write_byte(0xF1, 0xFF26);
-    cpu.mmu.write_byte(0x91, 0xFF40);
-    cpu.mmu.write_byte(0x00, 0xFF42);
-    cpu.mmu.write_byte(0x00, 0xFF43);
-    cpu.mmu.write_byte(0x00, 0xFF45);
-    cpu.mmu.write_byte(0xFC, 0xFF47);
-    cpu.mmu.write_byte(0xFF, 0xFF48);
-    cpu.mmu.write_byte(0xFF, 0xFF49);
-    cpu.mmu.write_byte(0x00, 0xFF4A);
-    cpu.mmu.write_byte(0xFF, 0xFF50);   // disable bootrom
-}
@@ -0,0 +1,29 @@
+use std::fmt;
+use std::io;
+
+use crate::patch::PatchError;
+
+#[derive(Debug)]
+pub enum EmuError {
+    Io { path: String, source: io::Error },
+    Patch { path: String, source: PatchError },
+    RomTooSmall(usize),
+    UnsupportedMbc(u8),
+    InvalidOpcode { opcode: u8, addr: u16 },
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmuError::Io { path, source } => write!(f, "cannot read {}: {}", path, source),
+            EmuError::Patch { path, source } => write!(f, "cannot apply patch {}: {}", path, source),
+            EmuError::RomTooSmall(size) => write!(f, "ROM is too small to be valid ({} bytes)", size),
+            EmuError::UnsupportedMbc(mbc) => write!(f, "unsupported memory bank controller: {:02X}", mbc),
+            EmuError::InvalidOpcode { opcode, addr } => {
+                write!(f, "invalid opcode {:02X} at {:04X}, CPU locked up", opcode, addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmuError {}
@@ -0,0 +1,61 @@
+use crate::frontend::gfx::Canvas;
+
+/// Mixes each frame with the ones shown before it, like the slow DMG LCD does. Games flicker
+/// sprites on alternate frames and count on it to show them half transparent.
+pub struct FrameBlend {
+    // weight of the previous frames, 0 shows each frame as it is
+    persistence: f32,
+    previous: Vec<f32>,
+}
+
+impl FrameBlend {
+    pub fn new(persistence: f32) -> FrameBlend {
+        FrameBlend { persistence, previous: vec![] }
+    }
+
+    pub fn apply(&mut self, canvas: &mut Canvas) {
+        // starts over when the screen size changes
+        if self.previous.len() != canvas.len() {
+            self.previous = canvas.iter().map(|&c| c as f32).collect();
+        }
+        for (prev, channel) in self.previous.iter_mut().zip(canvas.iter_mut()) {
+            *prev = *prev * self.persistence + *channel as f32 * (1.0 - self.persistence);
+            *channel = prev.round() as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::image::ImageBuffer;
+    use ::image::Rgba;
+
+    fn solid(value: u8) -> Canvas {
+        ImageBuffer::from_pixel(2, 2, Rgba([value, value, value, 255]))
+    }
+
+    #[test]
+    fn frame_blend_test() {
+        let mut blend = FrameBlend::new(0.5);
+        let mut frame = solid(0);
+        blend.apply(&mut frame);
+        assert_eq!(frame.get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+        // a sprite shown every other frame settles between on and off
+        let mut values = vec![];
+        for i in 0..20 {
+            let mut frame = solid(if i % 2 == 0 { 240 } else { 0 });
+            blend.apply(&mut frame);
+            values.push(frame.get_pixel(1, 1)[0]);
+        }
+        assert_eq!(values[18], 160);
+        assert_eq!(values[19], 80);
+
+        // 0 leaves frames alone
+        let mut blend = FrameBlend::new(0.0);
+        blend.apply(&mut solid(0));
+        let mut frame = solid(200);
+        blend.apply(&mut frame);
+        assert_eq!(frame.get_pixel(0, 0), &Rgba([200, 200, 200, 255]));
+    }
+}
@@ -0,0 +1,396 @@
+use ::image::ImageBuffer;
+use ::image::Rgba;
+
+use crate::frontend::gfx::Canvas;
+
+/// Upscaling applied to the frame before it's drawn, switched with F10
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub enum ScaleFilter {
+    #[default]
+    None,
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    Xbr2,
+    // each pixel drawn as a 3x3 dot with darker gaps between them
+    LcdGrid,
+}
+
+impl ScaleFilter {
+    pub fn from_name(name: &str) -> Option<ScaleFilter> {
+        match name {
+            "none" => Some(ScaleFilter::None),
+            "scale2x" | "epx" => Some(ScaleFilter::Scale2x),
+            "scale3x" => Some(ScaleFilter::Scale3x),
+            "hq2x" => Some(ScaleFilter::Hq2x),
+            "2xbr" | "xbr" => Some(ScaleFilter::Xbr2),
+            "lcd" => Some(ScaleFilter::LcdGrid),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ScaleFilter::None => "none",
+            ScaleFilter::Scale2x => "scale2x",
+            ScaleFilter::Scale3x => "scale3x",
+            ScaleFilter::Hq2x => "hq2x",
+            ScaleFilter::Xbr2 => "2xbr",
+            ScaleFilter::LcdGrid => "lcd",
+        }
+    }
+
+    pub fn next(self) -> ScaleFilter {
+        match self {
+            ScaleFilter::None => ScaleFilter::Scale2x,
+            ScaleFilter::Scale2x => ScaleFilter::Scale3x,
+            ScaleFilter::Scale3x => ScaleFilter::Hq2x,
+            ScaleFilter::Hq2x => ScaleFilter::Xbr2,
+            ScaleFilter::Xbr2 => ScaleFilter::LcdGrid,
+            ScaleFilter::LcdGrid => ScaleFilter::None,
+        }
+    }
+
+    pub fn apply(self, canvas: Canvas) -> Canvas {
+        match self {
+            ScaleFilter::None => canvas,
+            ScaleFilter::Scale2x => scale2x(&canvas),
+            ScaleFilter::Scale3x => scale3x(&canvas),
+            ScaleFilter::Hq2x => hq2x(&canvas),
+            ScaleFilter::Xbr2 => xbr2(&canvas),
+            ScaleFilter::LcdGrid => lcd_grid(&canvas),
+        }
+    }
+}
+
+type Pixel = Rgba<u8>;
+
+// the 3x3 neighbourhood of a pixel, repeating the edge pixels outside the image
+//   a b c
+//   d e f
+//   g h i
+struct Neighbours {
+    a: Pixel,
+    b: Pixel,
+    c: Pixel,
+    d: Pixel,
+    e: Pixel,
+    f: Pixel,
+    g: Pixel,
+    h: Pixel,
+    i: Pixel,
+}
+
+// the pixel `dx`, `dy` away, repeating the edge pixels outside the image
+fn pixel_around(src: &Canvas, x: u32, y: u32, dx: i32, dy: i32) -> Pixel {
+    let (w, h) = src.dimensions();
+    let x = (x as i32 + dx).max(0).min(w as i32 - 1);
+    let y = (y as i32 + dy).max(0).min(h as i32 - 1);
+    *src.get_pixel(x as u32, y as u32)
+}
+
+fn neighbours(src: &Canvas, x: u32, y: u32) -> Neighbours {
+    let p = |dx, dy| pixel_around(src, x, y, dx, dy);
+    Neighbours {
+        a: p(-1, -1),
+        b: p(0, -1),
+        c: p(1, -1),
+        d: p(-1, 0),
+        e: p(0, 0),
+        f: p(1, 0),
+        g: p(-1, 1),
+        h: p(0, 1),
+        i: p(1, 1),
+    }
+}
+
+/// Runs `scale_pixel` on each pixel, which gives the `factor` x `factor` block replacing it
+fn upscale(src: &Canvas, factor: u32, scale_pixel: impl Fn(&Neighbours) -> Vec<Pixel>) -> Canvas {
+    upscale_at(src, factor, |x, y| scale_pixel(&neighbours(src, x, y)))
+}
+
+// same as `upscale` for filters looking further than the 3x3 neighbourhood
+fn upscale_at(src: &Canvas, factor: u32, scale_pixel: impl Fn(u32, u32) -> Vec<Pixel>) -> Canvas {
+    let (w, h) = src.dimensions();
+    let mut dst = ImageBuffer::new(w * factor, h * factor);
+    for y in 0..h {
+        for x in 0..w {
+            let block = scale_pixel(x, y);
+            for (idx, pixel) in block.into_iter().enumerate() {
+                let idx = idx as u32;
+                dst.put_pixel(x * factor + idx % factor, y * factor + idx / factor, pixel);
+            }
+        }
+    }
+    dst
+}
+
+/// EPX/Scale2x: corners take the color of the two neighbours they touch when those match
+pub fn scale2x(src: &Canvas) -> Canvas {
+    upscale(src, 2, |n| {
+        let Neighbours { b, d, e, f, h, .. } = *n;
+        vec![
+            if d == b && b != f && d != h { d } else { e },
+            if b == f && b != d && f != h { f } else { e },
+            if d == h && d != b && h != f { d } else { e },
+            if h == f && d != h && b != f { f } else { e },
+        ]
+    })
+}
+
+/// Scale3x, the 3x version of Scale2x which also fills the edge middles
+pub fn scale3x(src: &Canvas) -> Canvas {
+    upscale(src, 3, |n| {
+        let Neighbours { a, b, c, d, e, f, g, h, i } = *n;
+        if b == h || d == f {
+            return vec![e; 9];
+        }
+        vec![
+            if d == b { d } else { e },
+            if (d == b && e != c) || (b == f && e != a) { b } else { e },
+            if b == f { f } else { e },
+            if (d == b && e != g) || (d == h && e != a) { d } else { e },
+            e,
+            if (b == f && e != i) || (h == f && e != c) { f } else { e },
+            if d == h { d } else { e },
+            if (d == h && e != i) || (h == f && e != g) { h } else { e },
+            if h == f { f } else { e },
+        ]
+    })
+}
+
+fn yuv(p: Pixel) -> [i32; 3] {
+    let [r, g, b] = [p[0] as i32, p[1] as i32, p[2] as i32];
+    [(r + g + b) / 3, (r - b) / 4 + 128, (2 * g - r - b) / 8 + 128]
+}
+
+/// HQx compares colors by how different they look rather than exactly, in YUV
+fn similar(p: Pixel, q: Pixel) -> bool {
+    let (p, q) = (yuv(p), yuv(q));
+    (p[0] - q[0]).abs() <= 48 && (p[1] - q[1]).abs() <= 7 && (p[2] - q[2]).abs() <= 6
+}
+
+// weighted average of the colors
+fn mix(colors: &[(Pixel, u32)]) -> Pixel {
+    let total: u32 = colors.iter().map(|&(_, weight)| weight).sum();
+    let channel = |c: usize| (colors.iter().map(|&(p, weight)| p[c] as u32 * weight).sum::<u32>() / total) as u8;
+    Rgba([channel(0), channel(1), channel(2), channel(3)])
+}
+
+/// A cut down HQ2x: edges are found like Scale2x but with HQx's YUV similarity, and
+/// corners on an edge are blended with the neighbours instead of copying them.
+/// It doesn't have the full HQ2x table of 256 neighbourhood patterns.
+pub fn hq2x(src: &Canvas) -> Canvas {
+    upscale(src, 2, |n| {
+        let Neighbours { a, b, c, d, e, f, g, h, i } = *n;
+        let corner = |side1: Pixel, side2: Pixel, diagonal: Pixel, other1: Pixel, other2: Pixel| {
+            if similar(side1, side2) && !similar(side1, other1) && !similar(side2, other2) {
+                // the diagonal pixel matching too means a solid area rather than a thin line
+                if similar(diagonal, side1) {
+                    mix(&[(e, 2), (side1, 1), (side2, 1)])
+                } else {
+                    mix(&[(e, 6), (side1, 1), (side2, 1)])
+                }
+            } else {
+                e
+            }
+        };
+        vec![
+            corner(d, b, a, h, f),
+            corner(b, f, c, d, h),
+            corner(d, h, g, b, f),
+            corner(h, f, i, d, b),
+        ]
+    })
+}
+
+// xBR's weighted YUV distance between two colors
+fn distance(p: Pixel, q: Pixel) -> i32 {
+    let (p, q) = (yuv(p), yuv(q));
+    48 * (p[0] - q[0]).abs() + 7 * (p[1] - q[1]).abs() + 6 * (p[2] - q[2]).abs()
+}
+
+/// Hyllian's 2xBR. Each corner looks at the 5x5 neighbourhood to decide whether an edge
+/// runs diagonally across it, by comparing the color distances along both diagonals,
+/// and blends in the color of the closer side pixel if it does.
+pub fn xbr2(src: &Canvas) -> Canvas {
+    upscale_at(src, 2, |x, y| {
+        // the corners from top left to bottom right, each seen as the bottom right one
+        // of a neighbourhood mirrored by `sx` and `sy`
+        [(-1, -1), (1, -1), (-1, 1), (1, 1)].iter().map(|&(sx, sy)| {
+            let p = |dx: i32, dy: i32| pixel_around(src, x, y, dx * sx, dy * sy);
+            let (e, f, h, i) = (p(0, 0), p(1, 0), p(0, 1), p(1, 1));
+            if e == f || e == h {
+                return e;
+            }
+            // how much the colors change going along each diagonal direction
+            let anti_diagonal = distance(e, p(1, -1)) + distance(e, p(-1, 1)) + distance(i, p(0, 2)) +
+                distance(i, p(2, 0)) + 4 * distance(h, f);
+            let diagonal = distance(h, p(-1, 0)) + distance(h, p(1, 2)) + distance(f, p(2, 1)) +
+                distance(f, p(0, -1)) + 4 * distance(e, i);
+            // an edge running along H and F cuts off the corner of E
+            if anti_diagonal < diagonal {
+                let side = if distance(e, f) <= distance(e, h) { f } else { h };
+                mix(&[(e, 1), (side, 1)])
+            } else {
+                e
+            }
+        }).collect()
+    })
+}
+
+/// Each pixel as a 3x3 dot, its right column and bottom row darker like the gaps of an LCD
+pub fn lcd_grid(src: &Canvas) -> Canvas {
+    upscale(src, 3, |n| {
+        let gap = mix(&[(n.e, 3), (Rgba([0, 0, 0, n.e[3]]), 1)]);
+        (0..9).map(|idx| if idx % 3 == 2 || idx / 3 == 2 { gap } else { n.e }).collect()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WHITE: Pixel = Rgba { data: [255, 255, 255, 255] };
+    const BLACK: Pixel = Rgba { data: [0, 0, 0, 255] };
+
+    // `#` for black, `.` for white
+    fn image(rows: &[&str]) -> Canvas {
+        let mut canvas = ImageBuffer::new(rows[0].len() as u32, rows.len() as u32);
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                canvas.put_pixel(x as u32, y as u32, if c == '#' { BLACK } else { WHITE });
+            }
+        }
+        canvas
+    }
+
+    fn assert_image(canvas: &Canvas, rows: &[&str]) {
+        let (w, h) = canvas.dimensions();
+        let got: Vec<String> = (0..h)
+            .map(|y| (0..w).map(|x| if *canvas.get_pixel(x, y) == BLACK { '#' } else { '.' }).collect())
+            .collect();
+        assert_eq!(got, rows);
+        assert!(canvas.pixels().all(|&p| p == BLACK || p == WHITE));
+    }
+
+    #[test]
+    fn scale2x_test() {
+        let src = image(&[
+            ".....",
+            ".#...",
+            "..#..",
+            "...#.",
+            ".....",
+        ]);
+        // the diagonal line gets its stairs filled in
+        assert_image(&scale2x(&src), &[
+            "..........",
+            "..........",
+            "..##......",
+            "..###.....",
+            "...###....",
+            "....###...",
+            ".....###..",
+            "......##..",
+            "..........",
+            "..........",
+        ]);
+        assert_image(&ScaleFilter::Scale2x.apply(image(&["#"])), &["##", "##"]);
+    }
+
+    #[test]
+    fn scale3x_test() {
+        let src = image(&[
+            ".....",
+            ".#...",
+            "..#..",
+            "...#.",
+            ".....",
+        ]);
+        assert_image(&scale3x(&src), &[
+            "...............",
+            "...............",
+            "...............",
+            "...###.........",
+            "...###.........",
+            "...####........",
+            ".....####......",
+            "......###......",
+            "......####.....",
+            "........####...",
+            ".........###...",
+            ".........###...",
+            "...............",
+            "...............",
+            "...............",
+        ]);
+    }
+
+    #[test]
+    fn hq2x_test() {
+        let src = image(&[
+            "#..",
+            ".#.",
+            "..#",
+        ]);
+        let dst = hq2x(&src);
+        assert_eq!(dst.dimensions(), (6, 6));
+        // the white corners along the line are shaded towards it
+        assert_eq!(dst.get_pixel(2, 1), &Rgba([191, 191, 191, 255]));
+        assert_eq!(dst.get_pixel(3, 0), &WHITE);
+        assert_eq!(dst.get_pixel(2, 2), &BLACK);
+        // colors too alike for HQx count as the same
+        assert!(similar(WHITE, Rgba([250, 250, 250, 255])));
+        assert!(!similar(WHITE, Rgba([255, 0, 0, 255])));
+    }
+
+    #[test]
+    fn xbr2_test() {
+        let src = image(&[
+            ".....",
+            ".#...",
+            "..#..",
+            "...#.",
+            ".....",
+        ]);
+        // the line is drawn as a thin diagonal, `+` for the half blended corners
+        let dst = xbr2(&src);
+        let got: Vec<String> = (0..10)
+            .map(|y| (0..10).map(|x| match dst.get_pixel(x, y)[0] { 255 => '.', 0 => '#', _ => '+' }).collect())
+            .collect();
+        assert_eq!(got, [
+            "..........",
+            "..........",
+            "..++......",
+            "..+#+.....",
+            "...+#+....",
+            "....+#+...",
+            ".....+#+..",
+            "......++..",
+            "..........",
+            "..........",
+        ]);
+        assert_eq!(dst.get_pixel(2, 2), &Rgba([127, 127, 127, 255]));
+    }
+
+    #[test]
+    fn lcd_grid_test() {
+        let dst = lcd_grid(&image(&["."]));
+        assert_eq!(dst.dimensions(), (3, 3));
+        assert_eq!(dst.get_pixel(0, 0), &WHITE);
+        assert_eq!(dst.get_pixel(1, 1), &WHITE);
+        assert_eq!(dst.get_pixel(2, 0), &Rgba([191, 191, 191, 255]));
+        assert_eq!(dst.get_pixel(0, 2), &Rgba([191, 191, 191, 255]));
+    }
+
+    #[test]
+    fn filter_names_test() {
+        assert_eq!(ScaleFilter::from_name("epx"), Some(ScaleFilter::Scale2x));
+        assert_eq!(ScaleFilter::from_name("xbr"), Some(ScaleFilter::Xbr2));
+        assert_eq!(ScaleFilter::from_name("sai"), None);
+        assert_eq!(ScaleFilter::Hq2x.next(), ScaleFilter::Xbr2);
+        assert_eq!(ScaleFilter::LcdGrid.next(), ScaleFilter::None);
+        assert_eq!(ScaleFilter::from_name(ScaleFilter::Xbr2.name()), Some(ScaleFilter::Xbr2));
+    }
+}
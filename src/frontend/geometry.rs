@@ -0,0 +1,73 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Window size and position, kept between runs
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct WindowGeometry {
+    pub size: [u32; 2],
+    pub position: Option<[i32; 2]>,
+}
+
+impl WindowGeometry {
+    /// `$XDG_CONFIG_HOME/gb-rust/window`, or under `~/.config` if it's not set
+    pub fn default_path() -> Option<PathBuf> {
+        let config = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(config.join("gb-rust").join("window"))
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Option<WindowGeometry>> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(WindowGeometry::parse(&text))
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, self.to_text())
+    }
+
+    /// `size <width> <height>` and optionally `position <x> <y>`, one per line
+    fn parse(text: &str) -> Option<WindowGeometry> {
+        let mut size = None;
+        let mut position = None;
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            let key = words.next();
+            let values: Vec<i64> = words.filter_map(|w| w.parse().ok()).collect();
+            match (key, values.as_slice()) {
+                (Some("size"), &[w, h]) if w > 0 && h > 0 => size = Some([w as u32, h as u32]),
+                (Some("position"), &[x, y]) => position = Some([x as i32, y as i32]),
+                _ => {}
+            }
+        }
+        Some(WindowGeometry { size: size?, position })
+    }
+
+    fn to_text(self) -> String {
+        let mut text = format!("size {} {}\n", self.size[0], self.size[1]);
+        if let Some([x, y]) = self.position {
+            text += &format!("position {} {}\n", x, y);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let geometry = WindowGeometry { size: [480, 432], position: Some([-10, 200]) };
+        assert_eq!(WindowGeometry::parse(&geometry.to_text()), Some(geometry));
+        let geometry = WindowGeometry { size: [160, 144], position: None };
+        assert_eq!(WindowGeometry::parse(&geometry.to_text()), Some(geometry));
+        assert_eq!(WindowGeometry::parse("position 1 2\n"), None);
+        assert_eq!(WindowGeometry::parse("size 0 144\n"), None);
+        assert_eq!(WindowGeometry::parse("size 320 x\n"), None);
+    }
+}
@@ -5,6 +5,10 @@ use crate::util::Array2D;
 use crate::gb::vram::*;
 use crate::gb::mmu::*;
 use crate::gb::ppu::*;
+use crate::frontend::ColorCorrection;
+use crate::frontend::palette::DmgPalettes;
+
+pub type Canvas = ImageBuffer<Rgba<u8>, Vec<u8>>;
 
 fn from_hex(rgba: u32) -> [f32; 4] {
     use std::mem::transmute;
@@ -106,17 +110,43 @@ fn draw_sprite(ppu: &Ppu, spr: &OamEntry, tileset: &Tileset, out_buf: &mut Array
 }
 
 
-pub fn render_framebuffer1(
-    window: &mut PistonWindow,
-    e: &Event,
+/// The DMG screen as shown, each shade colored by the palette of the layer it came from
+pub fn dmg_framebuffer_to_canvas(
     framebuffer: &[DmgColor],
-) {
-    let mut buf = Array2D::new(160, 144);
-    for idx in 0..160 * 144 {
-        let c = dmg_color_to_idx(framebuffer[idx]);
-        buf.set(idx % 160, idx / 160, c);
+    layers: &[DmgLayer],
+    palettes: &DmgPalettes,
+) -> Canvas {
+    let mut canvas = ImageBuffer::new(160, 144);
+    for (idx, (&color, &layer)) in framebuffer.iter().zip(layers).enumerate() {
+        let [r, g, b] = palettes.color(color, layer);
+        canvas.put_pixel(idx as u32 % 160, idx as u32 / 160, Rgba([r, g, b, 255]));
+    }
+    canvas
+}
+
+/// 15-bit colors as shown, the CGB screen or the SGB screen with its border
+pub fn rgb555_framebuffer_to_canvas(framebuffer: &[u16], width: u32, correction: ColorCorrection) -> Canvas {
+    let mut canvas = ImageBuffer::new(width, framebuffer.len() as u32 / width);
+    for (idx, &color) in framebuffer.iter().enumerate() {
+        let [r, g, b] = rgb555_to_rgb888(color, correction);
+        canvas.put_pixel(idx as u32 % width, idx as u32 / width, Rgba([r, g, b, 255]));
+    }
+    canvas
+}
+
+/// CGB colors are 15-bit, red in the lowest bits
+fn rgb555_to_rgb888(color: u16, correction: ColorCorrection) -> [u8; 3] {
+    let r = (color & 0x1F) as u32;
+    let g = (color >> 5 & 0x1F) as u32;
+    let b = (color >> 10 & 0x1F) as u32;
+    match correction {
+        ColorCorrection::None => [r, g, b].map(|c| (c << 3 | c >> 2) as u8),
+        ColorCorrection::Lcd => {
+            // weights out of 32 for each source channel, the brightest white ends up at 240
+            let mix = |wr: u32, wg: u32, wb: u32| ((r * wr + g * wg + b * wb).min(960) >> 2) as u8;
+            [mix(26, 4, 2), mix(0, 24, 8), mix(6, 4, 22)]
+        }
     }
-    render_buf(window, e, &buf);
 }
 
 //    pub fn render(&buf: )
@@ -178,17 +208,36 @@ fn render_tilemap_to_buf(vram: &Vram, _sc_x: u8, _sc_y: u8) -> Array2D {
 }
 
 fn render_buf(w: &mut PistonWindow, e: &Event, buf: &Array2D) {
-    use piston_window::*;
     let canvas = render_to_canvas(buf);
-    let texture: G2dTexture =
-        Texture::from_image(&mut w.factory, &canvas, &TextureSettings::new()).unwrap();
+    let screen = [canvas.width(), canvas.height()];
+    render_canvas(w, e, &canvas, screen);
+}
+
+/// `screen` is the size before any upscaling filter, the canvas is scaled to fit the window as
+/// the screen would be
+pub fn render_canvas(w: &mut PistonWindow, e: &Event, canvas: &Canvas, screen: [u32; 2]) {
+    use piston_window::*;
+    let settings = TextureSettings::new().filter(Filter::Nearest);
+    let texture: G2dTexture = Texture::from_image(&mut w.factory, canvas, &settings).unwrap();
+    let filter_scale = canvas.width() as f64 / screen[0] as f64;
     w.draw_2d(e, |c, gl| {
-        clear([0.3, 0.0, 0.0, 1.0], gl);
-        image(&texture, c.transform, gl);
+        // black bars around the screen when the window doesn't fit it exactly
+        clear([0.0, 0.0, 0.0, 1.0], gl);
+        let (scale, [x, y]) = letterbox(c.get_view_size(), screen);
+        let scale = scale / filter_scale;
+        image(&texture, c.transform.trans(x, y).scale(scale, scale), gl);
     });
 }
 
-fn render_to_canvas(buf: &Array2D) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+/// The largest whole scale at which the screen fits the view, and where it's centered.
+/// A view smaller than the screen gets it unscaled at the top left.
+fn letterbox(view: [f64; 2], screen: [u32; 2]) -> (f64, [f64; 2]) {
+    let scale = (view[0] / screen[0] as f64).min(view[1] / screen[1] as f64).floor().max(1.0);
+    let offset = |i: usize| ((view[i] - screen[i] as f64 * scale) / 2.0).floor().max(0.0);
+    (scale, [offset(0), offset(1)])
+}
+
+fn render_to_canvas(buf: &Array2D) -> Canvas {
     let width = buf.width() as u32;
     let height = buf.height() as u32;
     let mut canvas = ImageBuffer::new(width, height);
@@ -210,3 +259,27 @@ fn dmg_color_to_idx(c: DmgColor) -> u8 {
         DmgColor::White => 0,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn color_correction_test() {
+        assert_eq!(rgb555_to_rgb888(0x7FFF, ColorCorrection::None), [255, 255, 255]);
+        assert_eq!(rgb555_to_rgb888(0x001F, ColorCorrection::None), [255, 0, 0]);
+        assert_eq!(rgb555_to_rgb888(0x7FFF, ColorCorrection::Lcd), [240, 240, 240]);
+        // pure red gets some green and blue mixed in
+        let [r, g, b] = rgb555_to_rgb888(0x001F, ColorCorrection::Lcd);
+        assert!(r > 180 && g == 0 && b > 0);
+    }
+
+    #[test]
+    fn letterbox_test() {
+        assert_eq!(letterbox([480.0, 432.0], [160, 144]), (3.0, [0.0, 0.0]));
+        // a wide window gets bars on the sides
+        assert_eq!(letterbox([1920.0, 1080.0], [160, 144]), (7.0, [400.0, 36.0]));
+        assert_eq!(letterbox([500.0, 300.0], [160, 144]), (2.0, [90.0, 6.0]));
+        assert_eq!(letterbox([100.0, 100.0], [160, 144]), (1.0, [0.0, 0.0]));
+    }
+}
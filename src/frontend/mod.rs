@@ -1,4 +1,10 @@
 mod gfx;
+pub mod blend;
+pub mod filters;
+pub mod geometry;
+pub mod palette;
+
+use std::path::PathBuf;
 
 use crate::gb::Gb;
 use crate::gb::joypad::Joypad;
@@ -7,32 +13,133 @@ use crate::util::Array2D;
 use piston_window::*;
 use crate::gb::joypad::JoypadInterrupt;
 use crate::gb::Interrupts;
+use crate::gb::sgb::SGB_WIDTH;
+use crate::frontend::blend::FrameBlend;
+use crate::frontend::filters::ScaleFilter;
+use crate::frontend::geometry::WindowGeometry;
+use crate::frontend::palette::DmgPalettes;
+
+/// How 15-bit CGB colors are turned into RGB
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub enum ColorCorrection {
+    // colors scaled as they are, more saturated than on the real LCD
+    #[default]
+    None,
+    // channels bleed into each other and get darker, like on the CGB LCD
+    Lcd,
+}
+
+impl ColorCorrection {
+    pub fn from_name(name: &str) -> Option<ColorCorrection> {
+        match name {
+            "none" => Some(ColorCorrection::None),
+            "lcd" => Some(ColorCorrection::Lcd),
+            _ => None,
+        }
+    }
+}
 
 pub trait Frontend {
     fn get_input(&self) -> Joypad;
     fn render(&mut self, gb: &mut Gb);
+    /// True once the user closed the window
+    fn closed(&self) -> bool;
 }
 
+pub const DEFAULT_SCALE: u32 = 3;
+
 
 pub struct GlutinFrontend {
     window: PistonWindow,
+    pub color_correction: ColorCorrection,
+    pub dmg_palettes: DmgPalettes,
+    pub frame_blend: Option<FrameBlend>,
+    scale_filter: ScaleFilter,
+    fullscreen: bool,
+    // what the window looked like before going fullscreen
+    windowed_geometry: WindowGeometry,
+    geometry_path: Option<PathBuf>,
+    closed: bool,
 }
 
 impl GlutinFrontend {
-    pub fn new() -> GlutinFrontend {
+    /// `screen_dim` is 160x144, or 256x224 for the SGB border. The window is `scale` times
+    /// bigger, or as big as it was last time if no scale is given.
+    pub fn new(screen_dim: [u32; 2], scale: Option<u32>) -> GlutinFrontend {
         let bg_map_dim = [32 * 8, 32 * 8];
-        let screen_dim = [160, 144];
 
         let _window_dim = bg_map_dim;
         let _window_dim = [64 * 8, 64 * 8];
-        let window_dim = screen_dim;
 
-        let window: PistonWindow = WindowSettings::new("GB", window_dim)
+        let geometry_path = WindowGeometry::default_path();
+        let saved = geometry_path.as_ref().and_then(|path| match WindowGeometry::load(path) {
+            Ok(geometry) => geometry,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                eprintln!("Error when loading window geometry from {}: {}", path.display(), e);
+                None
+            }
+        });
+        let scaled = |scale: u32| [screen_dim[0] * scale, screen_dim[1] * scale];
+        let window_dim = match (scale, saved) {
+            (Some(scale), _) => scaled(scale),
+            (None, Some(saved)) => saved.size,
+            (None, None) => scaled(DEFAULT_SCALE),
+        };
+
+        let mut window: PistonWindow = WindowSettings::new("GB", window_dim)
             .exit_on_esc(true)
             .build()
             .unwrap();
+        if let Some([x, y]) = saved.and_then(|saved| saved.position) {
+            window.set_position([x, y]);
+        }
+
+        GlutinFrontend {
+            window,
+            color_correction: ColorCorrection::default(),
+            dmg_palettes: DmgPalettes::default(),
+            frame_blend: None,
+            scale_filter: ScaleFilter::default(),
+            fullscreen: false,
+            windowed_geometry: WindowGeometry { size: window_dim, position: None },
+            geometry_path,
+            closed: false,
+        }
+    }
 
-        GlutinFrontend { window }
+    fn geometry(&self) -> WindowGeometry {
+        let size = self.window.size();
+        let position = self.window.get_position().map(|pos| [pos.x, pos.y]);
+        WindowGeometry { size: [size.width as u32, size.height as u32], position }
+    }
+
+    /// The filter in use is shown in the window title
+    pub fn set_scale_filter(&mut self, filter: ScaleFilter) {
+        self.scale_filter = filter;
+        let title = match filter {
+            ScaleFilter::None => "GB".to_string(),
+            _ => format!("GB ({})", filter.name()),
+        };
+        self.window.set_title(title);
+    }
+
+    fn toggle_fullscreen(&mut self) {
+        if !self.fullscreen {
+            self.windowed_geometry = self.geometry();
+        }
+        self.fullscreen = !self.fullscreen;
+        let window = &self.window.window.window;
+        window.set_fullscreen(if self.fullscreen { Some(window.get_current_monitor()) } else { None });
+    }
+
+    fn save_geometry(&self) {
+        let geometry = if self.fullscreen { self.windowed_geometry } else { self.geometry() };
+        if let Some(path) = &self.geometry_path {
+            if let Err(e) = geometry.save(path) {
+                eprintln!("Error when saving window geometry to {}: {}", path.display(), e);
+            }
+        }
     }
 }
 
@@ -50,9 +157,38 @@ impl Frontend for GlutinFrontend {
                 gb.cpu.mmu._if |= Interrupts::JOYPAD;
             }
 
+            match e.press_args() {
+                Some(Button::Keyboard(Key::F10)) => self.set_scale_filter(self.scale_filter.next()),
+                Some(Button::Keyboard(Key::F11)) => self.toggle_fullscreen(),
+                _ => {}
+            }
+
             if let Some(_) = e.render_args() {
-                gfx::render_framebuffer1(&mut self.window, &e, &gb.cpu.mmu.ppu.framebuffer);
+                let mmu = &mut gb.cpu.mmu;
+                let ppu = &mmu.ppu;
+                let mut canvas = if let Some(sgb) = mmu.sgb.as_mut() {
+                    // a TV picture, no LCD to correct for
+                    let screen = sgb.render(&ppu.framebuffer);
+                    gfx::rgb555_framebuffer_to_canvas(screen, SGB_WIDTH as u32, ColorCorrection::None)
+                } else if ppu.color_output() {
+                    gfx::rgb555_framebuffer_to_canvas(&ppu.cgb_framebuffer, 160, self.color_correction)
+                } else {
+                    gfx::dmg_framebuffer_to_canvas(&ppu.framebuffer, &ppu.framebuffer_layers, &self.dmg_palettes)
+                };
+                if let Some(blend) = self.frame_blend.as_mut() {
+                    blend.apply(&mut canvas);
+                }
+                let screen = [canvas.width(), canvas.height()];
+                let canvas = self.scale_filter.apply(canvas);
+                gfx::render_canvas(&mut self.window, e, &canvas, screen);
             }
+        } else if !self.closed {
+            self.save_geometry();
+            self.closed = true;
         }
     }
+
+    fn closed(&self) -> bool {
+        self.closed
+    }
 }
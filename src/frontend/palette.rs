@@ -0,0 +1,94 @@
+use crate::gb::ppu::DmgLayer;
+use crate::gb::vram::DmgColor;
+
+/// 4 RGB colors, from white to black
+pub type ShadeColors = [[u8; 3]; 4];
+
+const GREY: ShadeColors = [[0xFF, 0xFF, 0xFF], [0xBF, 0xBF, 0xBF], [0x7F, 0x7F, 0x7F], [0x3F, 0x3F, 0x3F]];
+// the green-tinted screen of the original DMG
+const DMG: ShadeColors = [[0x9B, 0xBC, 0x0F], [0x8B, 0xAC, 0x0F], [0x30, 0x62, 0x30], [0x0F, 0x38, 0x0F]];
+const POCKET: ShadeColors = [[0xC4, 0xCF, 0xA1], [0x8B, 0x95, 0x6D], [0x4D, 0x53, 0x3C], [0x1F, 0x1F, 0x1F]];
+// the Game Boy Light with its backlight on
+const LIGHT: ShadeColors = [[0x00, 0xB5, 0x81], [0x00, 0x9A, 0x71], [0x00, 0x69, 0x4A], [0x00, 0x4F, 0x3B]];
+
+/// Parses a preset name or 4 comma separated RGB hex colors, e.g. `e0f8d0,88c070,346856,081820`
+pub fn shade_colors_from_name(name: &str) -> Option<ShadeColors> {
+    match name.to_lowercase().as_str() {
+        "grey" | "gray" => Some(GREY),
+        "dmg" => Some(DMG),
+        "pocket" => Some(POCKET),
+        "light" => Some(LIGHT),
+        _ => parse_hex_colors(name),
+    }
+}
+
+fn parse_hex_colors(list: &str) -> Option<ShadeColors> {
+    let mut colors = [[0; 3]; 4];
+    let mut parts = list.split(',');
+    for color in colors.iter_mut() {
+        let hex = parts.next()?.trim().trim_start_matches('#');
+        if hex.len() != 6 {
+            return None;
+        }
+        let rgb = u32::from_str_radix(hex, 16).ok()?;
+        *color = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(colors)
+}
+
+/// Colors of the DMG shades, the BG and each OBJ palette can have their own
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct DmgPalettes {
+    pub bg: ShadeColors,
+    pub obj0: ShadeColors,
+    pub obj1: ShadeColors,
+}
+
+impl DmgPalettes {
+    pub fn same(colors: ShadeColors) -> DmgPalettes {
+        DmgPalettes { bg: colors, obj0: colors, obj1: colors }
+    }
+
+    pub fn color(&self, color: DmgColor, layer: DmgLayer) -> [u8; 3] {
+        let colors = match layer {
+            DmgLayer::Bg => &self.bg,
+            DmgLayer::Obj0 => &self.obj0,
+            DmgLayer::Obj1 => &self.obj1,
+        };
+        colors[color.shade() as usize]
+    }
+}
+
+impl Default for DmgPalettes {
+    fn default() -> DmgPalettes {
+        DmgPalettes::same(GREY)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shade_colors_from_name_test() {
+        assert_eq!(shade_colors_from_name("Pocket"), Some(POCKET));
+        let colors = shade_colors_from_name("e0f8d0,88c070, 346856,#081820").unwrap();
+        assert_eq!(colors[0], [0xE0, 0xF8, 0xD0]);
+        assert_eq!(colors[3], [0x08, 0x18, 0x20]);
+        assert_eq!(shade_colors_from_name("e0f8d0,88c070,346856"), None);
+        assert_eq!(shade_colors_from_name("e0f8d0,88c070,346856,081820,000000"), None);
+        assert_eq!(shade_colors_from_name("e0f8d0,88c070,346856,08182g"), None);
+        assert_eq!(shade_colors_from_name("sepia"), None);
+    }
+
+    #[test]
+    fn layer_colors_test() {
+        let palettes = DmgPalettes { obj1: DMG, ..DmgPalettes::default() };
+        assert_eq!(palettes.color(DmgColor::White, DmgLayer::Bg), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(palettes.color(DmgColor::Black, DmgLayer::Obj0), [0x3F, 0x3F, 0x3F]);
+        assert_eq!(palettes.color(DmgColor::Black, DmgLayer::Obj1), [0x0F, 0x38, 0x0F]);
+    }
+}
@@ -0,0 +1,198 @@
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::path::Path;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum CheatKind {
+    // Game Genie - patches a byte of ROM, optionally only if it matches `compare`
+    GameGenie { addr: u16, val: u8, compare: Option<u8> },
+    // GameShark - writes a byte of RAM every frame, into `bank` of external or CGB work RAM if given
+    GameShark { addr: u16, val: u8, bank: Option<u8> },
+}
+
+#[derive(Debug, Clone)]
+pub struct Cheat {
+    pub code: String,
+    pub name: String,
+    pub enabled: bool,
+    pub kind: CheatKind,
+}
+
+#[derive(Default)]
+pub struct Cheats {
+    pub list: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Cheats { list: vec![] }
+    }
+
+    /// One cheat per line: `CODE [name]`. Lines starting with `#` are comments,
+    /// codes prefixed with `!` are loaded disabled.
+    pub fn from_file(path: &Path) -> std::io::Result<Cheats> {
+        let f = File::open(path)?;
+        let mut cheats = Cheats::new();
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (code, name) = match line.find(char::is_whitespace) {
+                Some(idx) => (&line[..idx], line[idx..].trim()),
+                None => (line, ""),
+            };
+            let (code, enabled) = match code.strip_prefix('!') {
+                Some(code) => (code, false),
+                None => (code, true),
+            };
+            if !cheats.add(code, name, enabled) {
+                eprintln!("Skipping invalid cheat code: {}", code);
+            }
+        }
+        Ok(cheats)
+    }
+
+    /// Returns false if the code could not be decoded
+    pub fn add(&mut self, code: &str, name: &str, enabled: bool) -> bool {
+        match decode(code) {
+            Some(kind) => {
+                self.list.push(Cheat {
+                    code: code.to_uppercase(),
+                    name: name.to_string(),
+                    enabled,
+                    kind,
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns new state of the cheat or None if there is no cheat with such index
+    pub fn toggle(&mut self, idx: usize) -> Option<bool> {
+        let cheat = self.list.get_mut(idx)?;
+        cheat.enabled = !cheat.enabled;
+        Some(cheat.enabled)
+    }
+
+    pub fn patch_rom(&self, addr: u16, original: u8) -> u8 {
+        for cheat in self.list.iter().filter(|c| c.enabled) {
+            if let CheatKind::GameGenie { addr: a, val, compare } = cheat.kind {
+                if a == addr && compare.is_none_or(|cmp| cmp == original) {
+                    return val;
+                }
+            }
+        }
+        original
+    }
+
+    pub fn ram_writes(&self) -> Vec<(u16, u8, Option<u8>)> {
+        self.list.iter()
+            .filter(|c| c.enabled)
+            .filter_map(|c| match c.kind {
+                CheatKind::GameShark { addr, val, bank } => Some((addr, val, bank)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+pub fn decode(code: &str) -> Option<CheatKind> {
+    let digits = hex_digits(code)?;
+    match (digits.len(), code.contains('-')) {
+        (6, _) | (9, _) => decode_game_genie(&digits),
+        (8, false) => decode_game_shark(&digits),
+        _ => None,
+    }
+}
+
+fn hex_digits(code: &str) -> Option<Vec<u8>> {
+    code.chars()
+        .filter(|&c| c != '-')
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect()
+}
+
+// ABC-DEF-GHI
+// AB   - new value
+// FCDE - address, F is xored with 0xF
+// GI   - compare value, rotated right by 2 and xored with 0xBA
+// H    - unused
+fn decode_game_genie(d: &[u8]) -> Option<CheatKind> {
+    let val = d[0] << 4 | d[1];
+    let addr = ((d[5] ^ 0xF) as u16) << 12 | (d[2] as u16) << 8 | (d[3] as u16) << 4 | d[4] as u16;
+    if addr >= 0x8000 {
+        return None;
+    }
+    let compare = if d.len() == 9 {
+        Some((d[6] << 4 | d[8]).rotate_right(2) ^ 0xBA)
+    } else {
+        None
+    };
+    Some(CheatKind::GameGenie { addr, val, compare })
+}
+
+// TTVVLLHH
+// TT   - 00 or 01 for the RAM bank currently mapped, 8X/9X for bank X
+// VV   - new value
+// HHLL - address, only RAM: external A000-BFFF, work C000-DFFF and high FF80-FFFE
+fn decode_game_shark(d: &[u8]) -> Option<CheatKind> {
+    let bank = match d[0] << 4 | d[1] {
+        0x00 | 0x01 => None,
+        tt @ 0x80..=0x9F => Some(tt & 0x0F),
+        _ => return None,
+    };
+    let val = d[2] << 4 | d[3];
+    let lo = d[4] << 4 | d[5];
+    let hi = d[6] << 4 | d[7];
+    let addr = (hi as u16) << 8 | lo as u16;
+    match addr {
+        0xA000..=0xDFFF | 0xFF80..=0xFFFE => Some(CheatKind::GameShark { addr, val, bank }),
+        // anything else would switch banks or poke IO registers every frame
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn game_genie_test() {
+        assert_eq!(
+            decode("00A-17B-C49"),
+            Some(CheatKind::GameGenie { addr: 0x4A17, val: 0x00, compare: Some(0xC8) })
+        );
+        assert_eq!(
+            decode("3EC-3AF"),
+            Some(CheatKind::GameGenie { addr: 0x0C3A, val: 0x3E, compare: None })
+        );
+        assert_eq!(decode("3EC-3A0"), None); // address outside of ROM
+    }
+
+    #[test]
+    fn game_shark_test() {
+        assert_eq!(decode("010238CD"), Some(CheatKind::GameShark { addr: 0xCD38, val: 0x02, bank: None }));
+        assert_eq!(decode("830200A0"), Some(CheatKind::GameShark { addr: 0xA000, val: 0x02, bank: Some(3) }));
+        assert_eq!(decode("01xx38CD"), None);
+        // MBC registers, IO and unknown types are refused
+        assert_eq!(decode("01020020"), None);
+        assert_eq!(decode("010240FF"), None);
+        assert_eq!(decode("0102FFFF"), None);
+        assert_eq!(decode("420238CD"), None);
+    }
+
+    #[test]
+    fn patch_rom_test() {
+        let mut cheats = Cheats::new();
+        assert!(cheats.add("00A-17B-C49", "", true));
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0x00);
+        assert_eq!(cheats.patch_rom(0x4A17, 0x12), 0x12); // compare value doesn't match
+        assert_eq!(cheats.toggle(0), Some(false));
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0xC8);
+        assert_eq!(cheats.toggle(1), None);
+    }
+}
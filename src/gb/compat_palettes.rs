@@ -0,0 +1,356 @@
+use crate::gb::mbc::Cartridge;
+use crate::gb::ppu::Ppu;
+
+/// Colors a DMG game gets on CGB hardware. The CGB boot ROM loads them into palette RAM:
+/// BG palette 0, OBJ palettes 0 and 1, all other palettes stay unused.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct CompatPalettes {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+impl CompatPalettes {
+    const fn new(bg: [u16; 4], obj0: [u16; 4], obj1: [u16; 4]) -> CompatPalettes {
+        CompatPalettes { bg, obj0, obj1 }
+    }
+
+    pub fn load(&self, ppu: &mut Ppu) {
+        ppu.bg_color_palettes.set_palette(0, self.bg);
+        ppu.obj_color_palettes.set_palette(0, self.obj0);
+        ppu.obj_color_palettes.set_palette(1, self.obj1);
+    }
+}
+
+// The 30 palettes of the boot ROM, 15-bit colors with red in the lowest bits
+const PALETTES: [u16; 30 * 4] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// 4 colors starting at any color of the palette table, not only at a palette boundary
+const fn colors(offset: usize) -> [u16; 4] {
+    [PALETTES[offset], PALETTES[offset + 1], PALETTES[offset + 2], PALETTES[offset + 3]]
+}
+
+// a combination of palettes of the table
+const fn combination(obj0: usize, obj1: usize, bg: usize) -> CompatPalettes {
+    CompatPalettes::new(colors(bg * 4), colors(obj0 * 4), colors(obj1 * 4))
+}
+
+// the boot ROM has a few combinations which start in the middle of a palette
+const fn raw_combination(obj0: usize, obj1: usize, bg: usize) -> CompatPalettes {
+    CompatPalettes::new(colors(bg), colors(obj0), colors(obj1))
+}
+
+// palette combinations for the OBJ0, OBJ1 and BG palettes
+const COMBINATIONS: [CompatPalettes; 51] = [
+    combination(4, 4, 29),                      // 0, right + A
+    combination(18, 18, 18),                    // 1, right
+    combination(20, 20, 20),
+    combination(24, 24, 24),                    // 3, down + A
+    combination(9, 9, 9),
+    combination(0, 0, 0),                       // 5, up
+    combination(27, 27, 27),                    // 6, right + B
+    combination(5, 5, 5),                       // 7, left + B
+    combination(12, 12, 12),                    // 8, down
+    combination(26, 26, 26),
+    combination(16, 8, 8),                      // 10
+    combination(4, 28, 28),
+    combination(4, 2, 2),
+    combination(3, 4, 4),
+    combination(4, 29, 29),
+    combination(28, 4, 28),
+    combination(2, 17, 2),
+    combination(16, 16, 8),
+    combination(4, 4, 7),
+    combination(4, 4, 18),
+    combination(4, 4, 20),                      // 20
+    combination(19, 19, 9),
+    combination(16, 22, 8),
+    combination(17, 17, 2),
+    combination(4, 4, 2),
+    combination(4, 4, 3),
+    combination(28, 28, 0),
+    combination(3, 3, 0),
+    combination(0, 0, 1),                       // 28, up + B
+    combination(18, 22, 18),
+    combination(20, 22, 20),                    // 30
+    combination(24, 22, 24),
+    raw_combination(4 * 4 - 1, 4 * 4 - 1, 11 * 4),
+    combination(17, 4, 13),
+    raw_combination(28 * 4 - 1, 0, 14 * 4),
+    raw_combination(28 * 4 - 1, 4 * 4, 15 * 4),
+    combination(19, 22, 9),
+    combination(16, 28, 10),
+    combination(4, 23, 28),
+    combination(17, 22, 2),
+    combination(4, 0, 2),                       // 40, left + A
+    combination(4, 28, 3),
+    combination(28, 3, 0),
+    combination(3, 28, 4),                      // 43, up + A
+    combination(21, 28, 4),
+    combination(3, 28, 0),
+    combination(25, 3, 28),
+    combination(0, 28, 8),
+    combination(4, 3, 28),                      // 48, left
+    combination(28, 3, 6),                      // 49, down + B
+    combination(4, 28, 29),                     // 50
+];
+
+/// Used for games the boot ROM doesn't know, same as right + A
+pub const DEFAULT: CompatPalettes = COMBINATIONS[0];
+
+// Title checksum, the 4th title letter for checksums shared by several games, and the
+// index in COMBINATIONS. The boot ROM only checks the letter for the entries at the end.
+const TITLE_PALETTES: [(u8, Option<u8>, usize); 94] = [
+    (0x00, None, 0),
+    (0x88, None, 4),          // ALLEY WAY
+    (0x16, None, 5),          // YAKUMAN
+    (0x36, None, 35),         // BASEBALL, GAME&WATCH 2
+    (0xD1, None, 34),         // TENNIS
+    (0xDB, None, 3),          // TETRIS
+    (0xF2, None, 31),         // QIX
+    (0x3C, None, 15),         // DR.MARIO
+    (0x8C, None, 10),         // RADARMISSION
+    (0x92, None, 5),          // F1RACE
+    (0x3D, None, 19),         // YOSSY NO TAMAGO
+    (0x5C, None, 36),
+    (0x58, None, 7),          // X
+    (0xC9, None, 37),
+    (0x3E, None, 30),         // YOSSY NO COOKIE
+    (0x70, None, 44),         // ZELDA
+    (0x1D, None, 21),
+    (0x59, None, 32),
+    (0x69, None, 31),         // TETRIS FLASH
+    (0x19, None, 20),         // DONKEY KONG
+    (0x35, None, 5),          // MARIO'S PICROSS
+    (0xA8, None, 33),
+    (0x14, None, 13),         // POKEMON RED, GAMEBOYCAMERA G
+    (0xAA, None, 14),         // POKEMON GREEN
+    (0x75, None, 5),          // PICROSS 2
+    (0x95, None, 29),         // YOSSY NO PANEPON
+    (0x99, None, 5),          // KIRAKIRA KIDS
+    (0x34, None, 18),         // GAMEBOY GALLERY
+    (0x6F, None, 9),          // POCKETCAMERA
+    (0x15, None, 3),
+    (0xFF, None, 2),          // BALLOON KID
+    (0x97, None, 26),         // KINGOFTHEZOO
+    (0x4B, None, 25),         // DMG FOOTBALL
+    (0x90, None, 25),         // WORLD CUP
+    (0x17, None, 41),         // OTHELLO
+    (0x10, None, 42),         // SUPER RC PRO-AM
+    (0x39, None, 26),         // DYNABLASTER
+    (0xF7, None, 45),         // BOY AND BLOB GB2
+    (0xF6, None, 42),         // MEGAMAN
+    (0xA2, None, 45),         // STAR WARS-NOA
+    (0x49, None, 36),
+    (0x4E, None, 38),         // WAVERACE
+    (0x43, None, 26),
+    (0x68, None, 42),         // LOLO2
+    (0xE0, None, 30),         // YOSHI'S COOKIE
+    (0x8B, None, 41),         // MYSTIC QUEST
+    (0xF0, None, 34),
+    (0xCE, None, 34),         // TOPRANKINGTENNIS
+    (0x0C, None, 5),          // MANSELL
+    (0x29, None, 42),         // MEGAMAN3
+    (0xE8, None, 6),          // SPACE INVADERS
+    (0xB7, None, 5),          // GAME&WATCH
+    (0x86, None, 33),         // DONKEYKONGLAND95
+    (0x9A, None, 25),         // ASTEROIDS/MISCMD
+    (0x52, None, 42),         // STREET FIGHTER 2
+    (0x01, None, 42),         // DEFENDER/JOUST
+    (0x9D, None, 40),         // KILLERINSTINCT95
+    (0x71, None, 2),          // TETRIS BLAST
+    (0x9C, None, 16),         // PINOCCHIO
+    (0xBD, None, 25),
+    (0x5D, None, 42),         // BA.TOSHINDEN
+    (0x6D, None, 42),         // NETTOU KOF 95
+    (0x67, None, 5),
+    (0x3F, None, 0),          // TETRIS PLUS
+    (0x6B, None, 39),         // DONKEYKONGLAND 3
+    (0xB3, Some(b'B'), 36),
+    (0x46, Some(b'E'), 22),   // SUPER MARIOLAND
+    (0x28, Some(b'F'), 25),   // GOLF
+    (0xA5, Some(b'A'), 6),    // SOLARSTRIKER
+    (0xC6, Some(b'A'), 32),   // GBWARS
+    (0xD3, Some(b'R'), 12),   // KAERUNOTAMENI
+    (0x27, Some(b'B'), 36),
+    (0x61, Some(b'E'), 11),   // POKEMON BLUE
+    (0x18, Some(b'K'), 39),   // DONKEYKONGLAND
+    (0x66, Some(b'E'), 18),   // GAMEBOY GALLERY2
+    (0x6A, Some(b'K'), 39),   // DONKEYKONGLAND 2
+    (0xBF, Some(b' '), 24),   // KID ICARUS
+    (0x0D, Some(b'R'), 31),   // TETRIS2
+    (0xF4, Some(b'-'), 50),
+    (0xB3, Some(b'U'), 17),   // MOGURANYA
+    (0x46, Some(b'R'), 46),
+    (0x28, Some(b'A'), 6),
+    (0xA5, Some(b'R'), 27),   // BT2RAGNAROKWORLD
+    (0xC6, Some(b' '), 0),    // KEN GRIFFEY JR
+    (0xD3, Some(b'I'), 47),
+    (0x27, Some(b'N'), 41),   // MAGNETIC SOCCER
+    (0x61, Some(b'A'), 41),   // VEGAS STAKES
+    (0x18, Some(b'I'), 0),
+    (0x66, Some(b'L'), 0),    // MILLI/CENTI/PEDE
+    (0x6A, Some(b'I'), 19),   // MARIO & YOSHI
+    (0xBF, Some(b'C'), 34),   // SOCCER
+    (0x0D, Some(b'E'), 23),   // POKEBOM
+    (0xF4, Some(b' '), 18),   // G&W GALLERY
+    (0xB3, Some(b'R'), 29),   // TETRIS ATTACK
+];
+
+/// Palettes picked by holding a direction, and optionally A or B, during the boot logo
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ManualPalette {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl ManualPalette {
+    pub fn from_name(name: &str) -> Option<ManualPalette> {
+        match name.to_lowercase().as_str() {
+            "up" => Some(ManualPalette::Up),
+            "up+a" => Some(ManualPalette::UpA),
+            "up+b" => Some(ManualPalette::UpB),
+            "left" => Some(ManualPalette::Left),
+            "left+a" => Some(ManualPalette::LeftA),
+            "left+b" => Some(ManualPalette::LeftB),
+            "down" => Some(ManualPalette::Down),
+            "down+a" => Some(ManualPalette::DownA),
+            "down+b" => Some(ManualPalette::DownB),
+            "right" => Some(ManualPalette::Right),
+            "right+a" => Some(ManualPalette::RightA),
+            "right+b" => Some(ManualPalette::RightB),
+            _ => None,
+        }
+    }
+
+    pub fn palettes(self) -> CompatPalettes {
+        let idx = match self {
+            ManualPalette::Up => 5,
+            ManualPalette::UpA => 43,
+            ManualPalette::UpB => 28,
+            ManualPalette::Left => 48,
+            ManualPalette::LeftA => 40,
+            ManualPalette::LeftB => 7,
+            ManualPalette::Down => 8,
+            ManualPalette::DownA => 3,
+            ManualPalette::DownB => 49,
+            ManualPalette::Right => 1,
+            ManualPalette::RightA => 0,
+            ManualPalette::RightB => 6,
+        };
+        COMBINATIONS[idx]
+    }
+}
+
+/// The boot ROM only colorizes Nintendo games, by the checksum of their title.
+/// Titles it doesn't know get the default palettes.
+pub fn for_cartridge(cart: &dyn Cartridge) -> CompatPalettes {
+    let old_licensee = cart.read_byte(0x14B);
+    let nintendo = match old_licensee {
+        0x01 => true,
+        // the new licensee code is two ASCII characters
+        0x33 => cart.read_byte(0x144) == b'0' && cart.read_byte(0x145) == b'1',
+        _ => false,
+    };
+    if !nintendo {
+        return DEFAULT;
+    }
+    let checksum = (0x134..0x144).fold(0u8, |sum, addr| sum.wrapping_add(cart.read_byte(addr)));
+    let fourth_letter = cart.read_byte(0x137);
+    TITLE_PALETTES.iter()
+        .find(|(sum, letter, _)| *sum == checksum && letter.is_none_or(|l| l == fourth_letter))
+        .map_or(DEFAULT, |&(_, _, idx)| COMBINATIONS[idx])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gb::mbc::NoMbc;
+
+    fn cart(title: &str, old_licensee: u8, new_licensee: &[u8; 2]) -> NoMbc {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title.as_bytes());
+        rom[0x144..0x146].copy_from_slice(new_licensee);
+        rom[0x14B] = old_licensee;
+        NoMbc::new(rom)
+    }
+
+    #[test]
+    fn title_checksum_test() {
+        const RED: [u16; 4] = colors(4 * 4);
+        const BLUE: [u16; 4] = colors(28 * 4);
+        assert_eq!(for_cartridge(&cart("POKEMON RED", 0x01, b"00")).bg, RED);
+        let palettes = for_cartridge(&cart("SUPER MARIOLAND", 0x01, b"00"));
+        assert_eq!(palettes, CompatPalettes::new(colors(8 * 4), colors(16 * 4), colors(22 * 4)));
+        // VEGAS STAKES has the same checksum as POKEMON BLUE, the 4th letter tells them apart
+        assert_eq!(for_cartridge(&cart("POKEMON BLUE", 0x01, b"00")).bg, BLUE);
+        assert_eq!(for_cartridge(&cart("VEGAS STAKES", 0x01, b"00")), COMBINATIONS[41]);
+        // the checksum again, but no 4th letter listed for it
+        assert_eq!(for_cartridge(&cart("VEGBS STAKER", 0x01, b"00")), DEFAULT);
+        assert_eq!(for_cartridge(&cart("ZELDA", 0x01, b"00")).obj0, [0x7FFF, 0x03E0, 0x0206, 0x0120]);
+        assert_eq!(for_cartridge(&cart("HELLO WORLD", 0x01, b"00")), DEFAULT);
+        // only for Nintendo games
+        assert_eq!(for_cartridge(&cart("POKEMON RED", 0x08, b"01")), DEFAULT);
+        assert_eq!(for_cartridge(&cart("POKEMON RED", 0x33, b"08")), DEFAULT);
+        assert_eq!(for_cartridge(&cart("POKEMON RED", 0x33, b"01")).bg, RED);
+    }
+
+    #[test]
+    fn raw_combination_test() {
+        // starts at the last color of palette 3
+        assert_eq!(COMBINATIONS[32].obj0, [0x0000, 0x7FFF, 0x421F, 0x1CF2]);
+        assert_eq!(COMBINATIONS[32].bg, colors(11 * 4));
+        assert_eq!(TITLE_PALETTES.iter().filter(|(_, letter, _)| letter.is_some()).count(), 29);
+    }
+
+    #[test]
+    fn load_test() {
+        let mut ppu = Ppu::new();
+        ManualPalette::from_name("Down+B").unwrap().palettes().load(&mut ppu);
+        assert_eq!(ppu.bg_color_palettes.color(0, 2), 0x012F);
+        assert_eq!(ppu.obj_color_palettes.color(0, 1), 0x7E8C);
+        assert_eq!(ppu.obj_color_palettes.color(1, 2), 0x0200);
+        assert_eq!(ManualPalette::from_name("up+c"), None);
+    }
+}
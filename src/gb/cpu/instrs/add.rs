@@ -19,7 +19,7 @@ impl Cpu {
 
     pub fn ADD_HL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         self.add8(x);
     }
 
@@ -5,6 +5,7 @@ impl Cpu {
     fn add(&mut self, x: u16) {
         let hl = self.hl();
         let sum = hl.wrapping_add(x);
+        self.idle_cycle();
         self.set_hl(sum);
         // flag Z is not affected
         self.set_h(util::half_carry_add16(hl, x));
@@ -37,6 +38,8 @@ impl Cpu {
         } else {
             self.sp = sp.wrapping_sub(-signed_n as u16);
         }
+        self.idle_cycle();
+        self.idle_cycle();
 
         self.set_z(false);
         self.set_n(false);
@@ -13,7 +13,7 @@ impl Cpu {
     pub fn BIT_aHL(&mut self, bit: u8) {
         assert!(bit < 8);
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         // FLAG C is not affected
         self.set_z(x & (1 << bit) == 0);
         self.set_h(true);
@@ -30,9 +30,9 @@ impl Cpu {
     pub fn SET_aHL(&mut self, bit: u8) {
         assert!(bit < 8);
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         let new_x = x | (1 << bit);
-        self.mmu.write_byte(new_x, hl);
+        self.write_cycle(new_x, hl);
     }
     pub fn RES_r(&mut self, bit: u8, r: Reg8) {
         assert!(bit < 8);
@@ -44,8 +44,8 @@ impl Cpu {
     pub fn RES_aHL(&mut self, bit: u8) {
         assert!(bit < 8);
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         let new_x = x & !(1 << bit);
-        self.mmu.write_byte(new_x, hl);
+        self.write_cycle(new_x, hl);
     }
 }
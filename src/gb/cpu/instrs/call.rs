@@ -1,41 +1,42 @@
 use crate::gb::cpu::*;
 
 impl Cpu {
-    pub fn call(&mut self, nn: u16, pred: bool) {
+    fn call(&mut self, nn: u16, pred: bool) {
         if pred {
             let pc = self.pc;
-            self.sp -= 2;
-            self.mmu.write_word(pc, self.sp);
+            self.idle_cycle();
+            self.push_word(pc);
             self.pc = nn;
         }
     }
 
-    fn call_nn(&mut self, pred: bool) {
+    fn call_nn(&mut self, pred: bool) -> u8 {
         let nn = self.read_immediate_word();
-        self.call(nn, pred)
+        self.call(nn, pred);
+        if pred { 6 } else { 3 }
     }
 
-    pub fn CALL(&mut self) {
+    pub fn CALL(&mut self) -> u8 {
         self.call_nn(true)
     }
 
-    pub fn CALL_Z(&mut self) {
+    pub fn CALL_Z(&mut self) -> u8 {
         let z = self.get_z();
-        self.call_nn(z);
+        self.call_nn(z)
     }
 
-    pub fn CALL_NZ(&mut self) {
+    pub fn CALL_NZ(&mut self) -> u8 {
         let z = self.get_z();
-        self.call_nn(!z);
+        self.call_nn(!z)
     }
 
-    pub fn CALL_C(&mut self) {
+    pub fn CALL_C(&mut self) -> u8 {
         let c = self.get_c();
-        self.call_nn(c);
+        self.call_nn(c)
     }
 
-    pub fn CALL_NC(&mut self) {
+    pub fn CALL_NC(&mut self) -> u8 {
         let c = self.get_c();
-        self.call_nn(!c);
+        self.call_nn(!c)
     }
 }
@@ -17,7 +17,7 @@ impl Cpu {
 
     pub fn CP_HL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         self.cp(x);
     }
 
@@ -10,8 +10,8 @@ impl Cpu {
 
     pub fn INC_aHL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
-        self.mmu.write_byte(x.wrapping_add(1), hl);
+        let x = self.read_cycle(hl);
+        self.write_cycle(x.wrapping_add(1), hl);
         self.set_flags_inc(x);
     }
 
@@ -1,10 +1,14 @@
 use crate::gb::cpu::*;
 
 impl Cpu {
-    fn jp(&mut self, pred: bool) {
+    fn jp(&mut self, pred: bool) -> u8 {
         let nn = self.read_immediate_word();
         if pred {
+            self.idle_cycle();
             self.pc = nn;
+            4
+        } else {
+            3
         }
     }
 
@@ -13,62 +17,66 @@ impl Cpu {
         self.pc = hl;
     }
 
-    pub fn JP(&mut self) {
+    pub fn JP(&mut self) -> u8 {
         self.jp(true)
     }
 
-    pub fn JP_Z(&mut self) {
+    pub fn JP_Z(&mut self) -> u8 {
         let z = self.get_z();
-        self.jp(z);
+        self.jp(z)
     }
 
-    pub fn JP_NZ(&mut self) {
+    pub fn JP_NZ(&mut self) -> u8 {
         let z = self.get_z();
-        self.jp(!z);
+        self.jp(!z)
     }
 
-    pub fn JP_C(&mut self) {
+    pub fn JP_C(&mut self) -> u8 {
         let c = self.get_c();
-        self.jp(c);
+        self.jp(c)
     }
 
-    pub fn JP_NC(&mut self) {
+    pub fn JP_NC(&mut self) -> u8 {
         let c = self.get_c();
-        self.jp(!c);
+        self.jp(!c)
     }
 
-    fn jr(&mut self, pred: bool) {
+    fn jr(&mut self, pred: bool) -> u8 {
         use std::mem;
-        let n = unsafe { mem::transmute::<u8, i8>(self.mmu.read_byte(self.pc)) };
-        self.pc += 1;
+        let n = unsafe { mem::transmute::<u8, i8>(self.read_cycle(self.pc)) };
+        self.pc = self.pc.wrapping_add(1);
         if pred {
+            self.idle_cycle();
             let pc = self.pc as i32;
             let new_pc = pc.wrapping_add(n as i32);
             self.pc = new_pc as u16;
+            3
+        } else {
+            2
         }
     }
 
-    pub fn JR(&mut self) {
+    pub fn JR(&mut self) -> u8 {
         self.jr(true)
     }
 
-    pub fn JR_Z(&mut self) {
+    pub fn JR_Z(&mut self) -> u8 {
         let z = self.get_z();
-        self.jr(z);
+        self.jr(z)
     }
 
-    pub fn JR_NZ(&mut self) {
+    pub fn JR_NZ(&mut self) -> u8 {
         let z = self.get_z();
-        self.jr(!z);
+        self.jr(!z)
     }
 
-    pub fn JR_C(&mut self) {
+    pub fn JR_C(&mut self) -> u8 {
         let c = self.get_c();
-        self.jr(c);
+        self.jr(c)
     }
 
-    pub fn JR_NC(&mut self) {
+    pub fn JR_NC(&mut self) -> u8 {
         let c = self.get_c();
-        self.jr(!c);
+        self.jr(!c)
     }
 }
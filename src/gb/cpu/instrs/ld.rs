@@ -15,83 +15,83 @@ impl Cpu {
     }
 
     pub fn LD_r_HL(&mut self, to: Reg8) {
-        let x = self.mmu.read_byte(self.hl());
+        let x = self.read_cycle(self.hl());
         *(self.get_mut_reg8(to)) = x;
     }
 
     pub fn LD_HL_r(&mut self, from: Reg8) {
         let hl = self.hl();
         let x = *self.get_reg8(from);
-        self.mmu.write_byte(x, hl);
+        self.write_cycle(x, hl);
     }
 
     pub fn ld_a_bc(&mut self) {
-        self.a = self.mmu.read_byte(self.bc())
+        self.a = self.read_cycle(self.bc())
     }
     pub fn ld_a_de(&mut self) {
-        self.a = self.mmu.read_byte(self.de())
+        self.a = self.read_cycle(self.de())
     }
     pub fn ld_a_nn(&mut self) {
         let nn = self.read_immediate_word();
-        self.a = self.mmu.read_byte(nn)
+        self.a = self.read_cycle(nn)
     }
 
     /// LD (HL), n
     pub fn ld__hl__n(&mut self) {
         let n = self.read_immediate_byte();
         let hl = self.hl();
-        self.mmu.write_byte(n, hl);
+        self.write_cycle(n, hl);
     }
 
     pub fn ld_bc_a(&mut self) {
         let bc = self.bc();
-        self.mmu.write_byte(self.a, bc)
+        self.write_cycle(self.a, bc)
     }
     pub fn ld_de_a(&mut self) {
         let de = self.de();
-        self.mmu.write_byte(self.a, de)
+        self.write_cycle(self.a, de)
     }
     pub fn ld_nn_a(&mut self) {
         let nn = self.read_immediate_word();
-        self.mmu.write_byte(self.a, nn)
+        self.write_cycle(self.a, nn)
     }
 
     pub fn ld__c__a(&mut self) {
-        self.mmu.write_byte(self.a, 0xFF00 + self.c as u16)
+        self.write_cycle(self.a, 0xFF00 + self.c as u16)
     }
     pub fn ld_a__c_(&mut self) {
-        self.a = self.mmu.read_byte(0xFF00 + self.c as u16)
+        self.a = self.read_cycle(0xFF00 + self.c as u16)
     }
 
     pub fn ldd_a_hl(&mut self) {
         let hl = self.hl();
-        self.a = self.mmu.read_byte(hl);
-        self.set_hl(hl - 1);
+        self.a = self.read_cycle(hl);
+        self.set_hl(hl.wrapping_sub(1));
     }
     pub fn ldi_a_hl(&mut self) {
         let hl = self.hl();
-        self.a = self.mmu.read_byte(hl);
-        self.set_hl(hl + 1);
+        self.a = self.read_cycle(hl);
+        self.set_hl(hl.wrapping_add(1));
     }
 
     pub fn ldd_hl_a(&mut self) {
         let hl = self.hl();
-        self.mmu.write_byte(self.a, hl);
-        self.set_hl(hl - 1);
+        self.write_cycle(self.a, hl);
+        self.set_hl(hl.wrapping_sub(1));
     }
     pub fn ldi_hl_a(&mut self) {
         let hl = self.hl();
-        self.mmu.write_byte(self.a, hl);
-        self.set_hl(hl + 1);
+        self.write_cycle(self.a, hl);
+        self.set_hl(hl.wrapping_add(1));
     }
 
     pub fn ldh_n_a(&mut self) {
         let n = self.read_immediate_byte();
-        self.mmu.write_byte(self.a, 0xFF00 + n as u16)
+        self.write_cycle(self.a, 0xFF00 + n as u16)
     }
     pub fn ldh_a_n(&mut self) {
         let n = self.read_immediate_byte();
-        self.a = self.mmu.read_byte(0xFF00 + n as u16)
+        self.a = self.read_cycle(0xFF00 + n as u16)
     }
 
     pub fn ld_bc_nn(&mut self) {
@@ -113,53 +113,52 @@ impl Cpu {
 
     pub fn ld_nn_sp(&mut self) {
         let nn = self.read_immediate_word();
-        self.mmu.write_word(self.sp, nn)
+        let (hi, lo) = util::split_word(self.sp);
+        self.write_cycle(lo, nn);
+        self.write_cycle(hi, nn.wrapping_add(1))
     }
 
     pub fn ld_sp_hl(&mut self) {
+        self.idle_cycle();
         self.sp = self.hl()
     }
 
     pub fn push_bc(&mut self) {
         let bc = self.bc();
-        self.sp -= 2;
-        self.mmu.write_word(bc, self.sp)
+        self.idle_cycle();
+        self.push_word(bc)
     }
     pub fn push_de(&mut self) {
         let de = self.de();
-        self.sp -= 2;
-        self.mmu.write_word(de, self.sp)
+        self.idle_cycle();
+        self.push_word(de)
     }
     pub fn push_hl(&mut self) {
         let hl = self.hl();
-        self.sp -= 2;
-        self.mmu.write_word(hl, self.sp)
+        self.idle_cycle();
+        self.push_word(hl)
     }
     pub fn push_af(&mut self) {
         let af = self.af();
-        self.sp -= 2;
-        self.mmu.write_word(af, self.sp)
+        self.idle_cycle();
+        self.push_word(af)
     }
 
     pub fn pop_bc(&mut self) {
-        let val = self.mmu.read_word(self.sp);
-        self.set_bc(val);
-        self.sp += 2
+        let val = self.pop_word();
+        self.set_bc(val)
     }
     pub fn pop_de(&mut self) {
-        let val = self.mmu.read_word(self.sp);
-        self.set_de(val);
-        self.sp += 2
+        let val = self.pop_word();
+        self.set_de(val)
     }
     pub fn pop_hl(&mut self) {
-        let val = self.mmu.read_word(self.sp);
-        self.set_hl(val);
-        self.sp += 2
+        let val = self.pop_word();
+        self.set_hl(val)
     }
     pub fn pop_af(&mut self) {
-        let val = self.mmu.read_word(self.sp);
-        self.set_af(val);
-        self.sp += 2
+        let val = self.pop_word();
+        self.set_af(val)
     }
 
     pub fn ldhl_sp_n(&mut self) {
@@ -172,6 +171,7 @@ impl Cpu {
             sp.wrapping_sub(-signed_n as u16)
         };
 
+        self.idle_cycle();
         self.set_hl(new_hl);
 
         self.set_z(false);
@@ -3,10 +3,27 @@ use crate::gb::cpu::*;
 impl Cpu {
     pub fn nop(&mut self) {}
     pub fn halt(&mut self) {
-        self.halted = true;
+        // with IME=0 and an interrupt already pending HALT doesn't halt,
+        // and PC fails to increment when the next opcode is fetched
+        if !self.ime && self.any_interrupt() {
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
     }
     pub fn stop(&mut self) {
-        self.stopped = true;
+        // the byte following STOP is skipped
+        self.pc = self.pc.wrapping_add(1);
+        self.mmu.timer.reset_div();
+        if self.mmu.speed_switch_armed() {
+            self.mmu.switch_speed();
+        } else {
+            self.mmu.ppu.blank_screen();
+            self.stopped = true;
+        }
+    }
+    pub fn lock_up(&mut self) {
+        self.locked_up = true;
     }
 
     pub fn cpl(&mut self) {
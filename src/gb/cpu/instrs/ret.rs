@@ -3,38 +3,48 @@ use crate::gb::cpu::*;
 impl Cpu {
     fn ret(&mut self, pred: bool) {
         if pred {
-            let addr = self.mmu.read_word(self.sp);
-            self.sp = self.sp.wrapping_add(2);
+            let addr = self.pop_word();
+            self.idle_cycle();
             self.pc = addr;
         }
     }
 
-    pub fn RET(&mut self) {
-        self.ret(true)
+    // conditional returns take an extra cycle to check the condition
+    fn ret_cond(&mut self, pred: bool) -> u8 {
+        self.idle_cycle();
+        self.ret(pred);
+        if pred { 5 } else { 2 }
     }
 
-    pub fn RET_Z(&mut self) {
+    pub fn RET(&mut self) -> u8 {
+        self.ret(true);
+        4
+    }
+
+    pub fn RET_Z(&mut self) -> u8 {
         let z = self.get_z();
-        self.ret(z);
+        self.ret_cond(z)
     }
 
-    pub fn RET_NZ(&mut self) {
+    pub fn RET_NZ(&mut self) -> u8 {
         let z = self.get_z();
-        self.ret(!z);
+        self.ret_cond(!z)
     }
 
-    pub fn RET_C(&mut self) {
+    pub fn RET_C(&mut self) -> u8 {
         let c = self.get_c();
-        self.ret(c);
+        self.ret_cond(c)
     }
 
-    pub fn RET_NC(&mut self) {
+    pub fn RET_NC(&mut self) -> u8 {
         let c = self.get_c();
-        self.ret(!c);
+        self.ret_cond(!c)
     }
 
-    pub fn RETI(&mut self) {
+    // unlike EI, enables interrupts right away
+    pub fn RETI(&mut self) -> u8 {
         self.ret(true);
-        self.ei_pending = true;
+        self.ime = true;
+        4
     }
 }
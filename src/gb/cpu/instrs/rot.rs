@@ -79,9 +79,9 @@ impl Cpu {
 
     pub fn RLC_aHL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         let result = x.rotate_left(1);
-        self.mmu.write_byte(result, hl);
+        self.write_cycle(result, hl);
         self.set_c(x >> 7 > 0);
         self.set_z(result == 0);
         self.set_h(false);
@@ -89,10 +89,10 @@ impl Cpu {
     }
     pub fn RL_aHL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         let old_c = self.get_c();
         let result = x << 1 | if old_c { 1 } else { 0 };
-        self.mmu.write_byte(result, hl);
+        self.write_cycle(result, hl);
         self.set_c(x >> 7 > 0);
 
         self.set_z(result == 0);
@@ -101,20 +101,20 @@ impl Cpu {
     }
     pub fn RRC_aHL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         let result = x.rotate_right(1);
         self.set_c(x << 7 > 0);
-        self.mmu.write_byte(result, hl);
+        self.write_cycle(result, hl);
         self.set_z(result == 0);
         self.set_h(false);
         self.set_n(false);
     }
     pub fn RR_aHL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         let old_c = self.get_c();
         let result = x >> 1 | if old_c { 1 << 7 } else { 0 };
-        self.mmu.write_byte(result, hl);
+        self.write_cycle(result, hl);
         self.set_c(x << 7 > 0);
         self.set_z(result == 0);
         self.set_h(false);
@@ -3,8 +3,8 @@ use crate::gb::cpu::*;
 impl Cpu {
     fn rst(&mut self, addr: u16) {
         let pc = self.pc;
-        self.sp -= 2;
-        self.mmu.write_word(pc, self.sp);
+        self.idle_cycle();
+        self.push_word(pc);
         self.pc = addr;
     }
 
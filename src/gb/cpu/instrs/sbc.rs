@@ -21,7 +21,7 @@ impl Cpu {
 
     pub fn SBC_HL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         self.sbc(x);
     }
 
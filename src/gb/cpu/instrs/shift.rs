@@ -33,8 +33,8 @@ impl Cpu {
 
     pub fn SLA_aHL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
-        self.mmu.write_byte(x << 1, hl);
+        let x = self.read_cycle(hl);
+        self.write_cycle(x << 1, hl);
         self.set_c(x >> 7 > 0);
         self.set_z(x << 1 == 0);
         self.set_h(false);
@@ -43,9 +43,9 @@ impl Cpu {
 
     pub fn SRA_aHL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         let new_x = x >> 1 | (x & (1 << 7));
-        self.mmu.write_byte(new_x, hl);
+        self.write_cycle(new_x, hl);
         self.set_c(x & 1 > 0);
         self.set_z(new_x == 0);
         self.set_h(false);
@@ -54,9 +54,9 @@ impl Cpu {
 
     pub fn SRL_aHL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         let new_x = x >> 1;
-        self.mmu.write_byte(new_x, hl);
+        self.write_cycle(new_x, hl);
         self.set_c(x & 1 > 0);
         self.set_z(new_x == 0);
         self.set_h(false);
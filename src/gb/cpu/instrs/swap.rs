@@ -13,8 +13,8 @@ impl Cpu {
 
     pub fn SWAP_aHL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
-        self.mmu.write_byte(swap_nibbles(x), hl);
+        let x = self.read_cycle(hl);
+        self.write_cycle(swap_nibbles(x), hl);
         self.set_z(x == 0);
         self.set_n(false);
         self.set_h(false);
@@ -18,7 +18,7 @@ impl Cpu {
 
     pub fn XOR_HL(&mut self) {
         let hl = self.hl();
-        let x = self.mmu.read_byte(hl);
+        let x = self.read_cycle(hl);
         self.xor(x);
     }
 
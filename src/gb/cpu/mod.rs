@@ -27,12 +27,14 @@ pub struct Cpu {
 
     pub halted: bool,
     pub stopped: bool,
+    // set after executing an invalid opcode, only a reset gets the CPU out of it
+    pub locked_up: bool,
+    // set by the HALT bug, the next opcode fetch doesn't increment PC
+    halt_bug: bool,
 
     ei_pending: bool,
 
     pub ime: bool, // Interrupt Master Enable Flag (Write Only)
-
-    cycles_busy: u32,
 }
 
 impl Cpu {
@@ -52,20 +54,15 @@ impl Cpu {
             clock: 0,
             halted: false,
             stopped: false,
+            locked_up: false,
+            halt_bug: false,
             ei_pending: false,
             ime: false,
-            cycles_busy: 0,
         }
     }
 
-    pub fn is_busy(&self) -> bool {
-        self.cycles_busy > 0
-    }
-
-    pub fn set_busy(&mut self, cycles: u32) {
-        self.cycles_busy = cycles
-    }
-
+    /// EI takes effect only after the instruction following it,
+    /// so this is called before executing each instruction
     pub fn handle_ei_delay(&mut self) {
         if self.ei_pending {
             self.ime = true;
@@ -73,14 +70,52 @@ impl Cpu {
         }
     }
 
-    pub fn pass_cycle(&mut self) {
-        self.cycles_busy -= 1;
+    // Every memory access takes one machine cycle, the rest of the machine
+    // is ticked before the access so it sees the state at the end of the cycle.
+    pub fn tick(&mut self) {
+        self.mmu.tick();
         self.clock += 1;
     }
 
+    /// Machine cycle spent on internal work, without touching the bus
+    pub fn idle_cycle(&mut self) {
+        self.tick();
+    }
+
+    pub fn read_cycle(&mut self, addr: u16) -> u8 {
+        self.tick();
+        self.mmu.cpu_read_byte(addr)
+    }
+
+    pub fn write_cycle(&mut self, val: u8, addr: u16) {
+        self.tick();
+        self.mmu.cpu_write_byte(val, addr);
+    }
+
+    // the high byte is pushed first
+    pub fn push_word(&mut self, val: u16) {
+        let (hi, lo) = util::split_word(val);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(hi, self.sp);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(lo, self.sp);
+    }
+
+    pub fn pop_word(&mut self) -> u16 {
+        let lo = self.read_cycle(self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        let hi = self.read_cycle(self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        util::concat(hi, lo)
+    }
+
     pub fn fetch_opcode_byte(&mut self) -> u8 {
-        let opcode = self.mmu.read_byte(self.pc);
-        self.pc = self.pc.wrapping_add(1);
+        let opcode = self.read_cycle(self.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
         opcode
     }
 
@@ -157,15 +192,15 @@ impl Cpu {
     }
 
     pub fn read_immediate_byte(&mut self) -> u8 {
-        let n = self.mmu.read_byte(self.pc);
+        let n = self.read_cycle(self.pc);
         self.pc = self.pc.wrapping_add(1);
         n
     }
 
     pub fn read_immediate_word(&mut self) -> u16 {
-        let nn = self.mmu.read_word(self.pc);
-        self.pc = self.pc.wrapping_add(2);
-        nn
+        let lo = self.read_immediate_byte();
+        let hi = self.read_immediate_byte();
+        util::concat(hi, lo)
     }
 
     pub fn get_reg8(&self, r: Reg8) -> &u8 {
@@ -194,30 +229,48 @@ impl Cpu {
         }
     }
 
-    pub fn any_interrupt(&mut self) -> bool {
-        !self.mmu._if.is_empty()
+    /// True if any enabled interrupt is requested, regardless of IME
+    pub fn any_interrupt(&self) -> bool {
+        !(self.mmu.ie & self.mmu._if).is_empty()
     }
 
     /// Returns true if any interrupt got handled
     pub fn handle_interrupts(&mut self) -> bool {
-        if self.ime {
-            //            Bit 0: V-Blank  Interrupt Enable  (INT 40h)  (1=Enable)
-            //            Bit 1: LCD STAT Interrupt Enable  (INT 48h)  (1=Enable)
-            //            Bit 2: Timer    Interrupt Enable  (INT 50h)  (1=Enable)
-            //            Bit 3: Serial   Interrupt Enable  (INT 58h)  (1=Enable)
-            //            Bit 4: Joypad   Interrupt Enable  (INT 60h)  (1=Enable)
-            for bit in 0..5 {
-                let int_addr = 0x40 + (0x08 * bit);
-                let flag = Interrupts::from_bits_truncate(1 << bit);
-                if self.check_interrupt(flag) {
-                    self.call(int_addr, true);
-                    self.ime = false;
-                    self.mmu._if -= flag;
-                    return true;
-                }
-            }
+        if !self.ime || !self.any_interrupt() {
+            return false;
         }
-        return false;
+        // two wait cycles, the two pushes and one cycle to set PC
+        self.ime = false;
+        self.idle_cycle();
+        self.idle_cycle();
+        let (pc_hi, pc_lo) = util::split_word(self.pc);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(pc_hi, self.sp);
+        // the interrupt is picked only after the high byte got pushed, which
+        // may have overwritten IE. With nothing left to handle the CPU jumps to 0
+        let pending = self.highest_priority_interrupt();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(pc_lo, self.sp);
+        self.pc = match pending {
+            Some(flag) => {
+                self.mmu._if -= flag;
+                interrupt_vector(flag)
+            }
+            None => 0x0000,
+        };
+        self.idle_cycle();
+        true
+    }
+
+    //            Bit 0: V-Blank  Interrupt Enable  (INT 40h)  (1=Enable)
+    //            Bit 1: LCD STAT Interrupt Enable  (INT 48h)  (1=Enable)
+    //            Bit 2: Timer    Interrupt Enable  (INT 50h)  (1=Enable)
+    //            Bit 3: Serial   Interrupt Enable  (INT 58h)  (1=Enable)
+    //            Bit 4: Joypad   Interrupt Enable  (INT 60h)  (1=Enable)
+    fn highest_priority_interrupt(&self) -> Option<Interrupts> {
+        (0..5)
+            .map(|bit| Interrupts::from_bits_truncate(1 << bit))
+            .find(|&flag| self.check_interrupt(flag))
     }
 
     fn check_interrupt(&self, flag: Interrupts) -> bool {
@@ -227,6 +280,10 @@ impl Cpu {
     }
 }
 
+fn interrupt_vector(flag: Interrupts) -> u16 {
+    0x40 + 0x08 * flag.bits().trailing_zeros() as u16
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Reg8 {
     A,
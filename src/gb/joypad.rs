@@ -1,4 +1,6 @@
 use piston_window::*;
+use crate::gb::sgb::PacketReader;
+use crate::gb::sgb::MLT_REQ;
 
 // Bit 7 - Not used
 // Bit 6 - Not used
@@ -23,6 +25,12 @@ pub struct Joypad {
     dir_select: bool,
     btn_select: bool,
 
+    // SGB commands sent through P14/P15, only decoded on a Super Game Boy
+    sgb_packets: Option<PacketReader>,
+    sgb_command: Option<Vec<u8>>,
+    // MLT_REQ: number of controllers, and the one whose ID reads back with no group selected
+    players: u8,
+    player: u8,
 }
 
 pub struct JoypadInterrupt {}
@@ -80,10 +88,38 @@ impl Joypad {
             a: false,
             dir_select: true,
             btn_select: false,
+            sgb_packets: None,
+            sgb_command: None,
+            players: 1,
+            player: 0,
         }
     }
 
+    pub fn enable_sgb(&mut self) {
+        self.sgb_packets = Some(PacketReader::default());
+    }
+
+    /// The last SGB command received, other than MLT_REQ which is handled here
+    pub fn take_sgb_command(&mut self) -> Option<Vec<u8>> {
+        self.sgb_command.take()
+    }
+
+    /// True if a button is pressed in a selected group, which wakes the CPU from STOP
+    pub fn any_selected_pressed(&self) -> bool {
+        self.read_byte() & 0x0F != 0x0F
+    }
+
     pub fn read_byte(&self) -> u8 {
+        // only the first controller has buttons
+        if self.player != 0 {
+            return if self.dir_select {
+                0b1110_1111
+            } else if self.btn_select {
+                0b1101_1111
+            } else {
+                0b1111_0000 | (0x0F - self.player)
+            };
+        }
         let byte =
             if self.dir_select {
                 0b1110_1111
@@ -104,8 +140,27 @@ impl Joypad {
     }
 
     pub fn write_byte(&mut self, n: u8) {
+        let p15_rises = self.btn_select && is_bit_set(n, BUTTON_SELECT_BIT);
         self.btn_select = is_bit_unset(n, BUTTON_SELECT_BIT);
         self.dir_select = is_bit_unset(n, DIRECTION_SELECT_BIT);
+        if let Some(packets) = self.sgb_packets.as_mut() {
+            // the next controller is selected each time P15 goes back high
+            if p15_rises && self.players > 1 {
+                self.player = (self.player + 1) % self.players;
+            }
+            if let Some(command) = packets.write(n) {
+                if command[0] >> 3 == MLT_REQ {
+                    self.players = match command[1] & 0b11 {
+                        1 => 2,
+                        3 => 4,
+                        _ => 1,
+                    };
+                    self.player = 0;
+                } else {
+                    self.sgb_command = Some(command);
+                }
+            }
+        }
     }
 }
 
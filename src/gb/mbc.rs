@@ -2,13 +2,28 @@ pub trait Cartridge {
     fn read_byte(&self, addr: u16) -> u8;
     fn write_byte(&mut self, addr: u16, val: u8);
 
+    /// Writes external RAM in `bank` whichever bank is mapped, for cheats
+    fn write_ram_bank(&mut self, _bank: u8, _addr: u16, _val: u8) {}
+
     fn get_name(&self) -> String {
+        // the last title byte is the CGB flag on newer cartridges
+        let end = if self.supports_cgb() { 0x143 } else { 0x144 };
         let mut ascii = vec![];
-        for addr in 0x134..0x144 {
+        for addr in 0x134..end {
             ascii.push(self.read_byte(addr))
         }
         String::from_utf8(ascii.to_vec()).unwrap_or("unknown".to_string())
     }
+
+    /// Header byte 0x143 has bit 7 set for games using CGB features, 0xC0 for CGB only games
+    fn supports_cgb(&self) -> bool {
+        self.read_byte(0x143) & 0x80 != 0
+    }
+
+    /// The SGB only listens to commands from games flagged for it, with the old licensee set to 0x33
+    fn supports_sgb(&self) -> bool {
+        self.read_byte(0x146) == 0x03 && self.read_byte(0x14B) == 0x33
+    }
 }
 
 pub struct Mbc1 {
@@ -37,16 +52,16 @@ impl Mbc1 {
 impl Cartridge for Mbc1 {
     fn read_byte(&self, addr: u16) -> u8 {
         match addr {
-            0x0000...0x3FFF => self.rom[addr as usize],
+            0x0000...0x3FFF => *self.rom.get(addr as usize).unwrap_or(&0xFF),
             0x4000...0x7FFF => {
                 let idx = 0x4000 * self.rom_bank + (addr - 0x4000) as u32;
-                self.rom[idx as usize]
+                self.rom[idx as usize % self.rom.len()]
             }
             0xA000...0xBFFF => {
-                let idx = 0x2000 * self.ram_bank + (addr - 0x2000) as u32;
+                let idx = 0x2000 * self.ram_bank + (addr - 0xA000) as u32;
                 self.ram[idx as usize]
             }
-            _ => panic!("Unandled address: {:X}", addr),
+            _ => 0xFF, // not mapped to the cartridge
         }
     }
     fn write_byte(&mut self, addr: u16, val: u8) {
@@ -79,9 +94,14 @@ impl Cartridge for Mbc1 {
                 let idx = 0x2000 * self.ram_bank + (addr - 0xA000) as u32;
                 self.ram[idx as usize] = val;
             }
-            _ => panic!("Unandled address: {:X}", addr),
+            _ => {} // not mapped to the cartridge
         }
     }
+    fn write_ram_bank(&mut self, bank: u8, addr: u16, val: u8) {
+        let idx = 0x2000 * bank as usize + (addr - 0xA000) as usize;
+        let len = self.ram.len();
+        self.ram[idx % len] = val;
+    }
 }
 
 pub struct NoMbc {
@@ -96,7 +116,25 @@ impl NoMbc {
 
 impl Cartridge for NoMbc {
     fn read_byte(&self, addr: u16) -> u8 {
-        self.rom[addr as usize]
+        *self.rom.get(addr as usize).unwrap_or(&0xFF)
     }
     fn write_byte(&mut self, addr: u16, val: u8) {}
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn short_mbc1_rom_test() {
+        let mut rom = vec![0; 0x200];
+        rom[0x1FF] = 0x42;
+        let cart = Mbc1::new(rom);
+        assert_eq!(cart.read_byte(0x01FF), 0x42);
+        // past the end of the ROM
+        assert_eq!(cart.read_byte(0x0200), 0xFF);
+        assert_eq!(cart.read_byte(0x3FFF), 0xFF);
+        // the switchable bank wraps around the ROM
+        assert_eq!(cart.read_byte(0x41FF), 0x42);
+    }
+}
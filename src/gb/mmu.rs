@@ -1,19 +1,23 @@
 use crate::gb::Interrupts;
+use crate::gb::cheats::Cheats;
 use crate::gb::joypad::Joypad;
 use crate::gb::mbc::*;
 use crate::gb::ppu::*;
+use crate::gb::sgb::Sgb;
 use crate::gb::timer::Timer;
-use crate::gb::timer::TimerControl;
 use crate::util;
 
-const VRAM_SIZE: usize = 8 * 1024;
-const WORK_RAM_SIZE: usize = 8 * 1024;
+// CGB has 2 VRAM banks and 8 work RAM banks, DMG only uses the first ones
+const VRAM_SIZE: usize = 2 * VRAM_BANK_SIZE;
+const WORK_RAM_BANK_SIZE: usize = 4 * 1024;
+const WORK_RAM_SIZE: usize = 8 * WORK_RAM_BANK_SIZE;
 const OAM_SIZE: usize = 160;
 const IO_SIZE: usize = 128;
 const ZERO_RAM_SIZE: usize = 128;
+const HDMA_BLOCK_SIZE: u8 = 16;
 
 pub struct Mmu {
-    bootrom: Vec<u8>,
+    bootrom: Option<Vec<u8>>,
     pub cart: Box<Cartridge>,
     pub vram: [u8; VRAM_SIZE],
     work_ram: [u8; WORK_RAM_SIZE],
@@ -27,14 +31,42 @@ pub struct Mmu {
     pub timer: Timer,
     pub joypad: Joypad,
     pub ppu: Ppu,
-    dma_cycles_left: u32,
+    pub cheats: Cheats,
+    // Super Game Boy palettes and border, set for SGB enhanced games on the SGB model
+    pub sgb: Option<Sgb>,
+    // set when the PPU enters vblank, cleared by whoever presents the frame
+    pub frame_ready: bool,
+    // CGB only registers are ignored unless this is set
+    pub cgb_mode: bool,
+    pub double_speed: bool,
+    speed_switch_armed: bool,
+    // VBK, selects the VRAM bank seen at 8000-9FFF
+    vram_bank: u8,
+    // SVBK, selects the work RAM bank seen at D000-DFFF, 0 picks bank 1
+    work_ram_bank: u8,
+    // in double speed mode the PPU only advances every other CPU machine cycle
+    ppu_cycle_skipped: bool,
+    // last value written to FF46
     dma_src: u8,
-    restrict_vram_oam: bool,
+    // transfer requested by writing FF46, starts after the given delay
+    dma_request: Option<(u16, u8)>,
+    // source of the running transfer and the next byte to copy
+    dma_active: Option<(u16, u8)>,
+    // CGB VRAM DMA, HDMA1-4 set the source and the VRAM destination
+    hdma_src: u16,
+    hdma_dst: u16,
+    // blocks of 16 bytes left to copy
+    hdma_blocks_left: u8,
+    hdma_active: bool,
+    // copies a block at the start of each HBlank instead of everything at once
+    hdma_hblank: bool,
+    // bytes left of the block being copied, the CPU is paused meanwhile
+    hdma_block_bytes: u8,
 }
 
 impl Mmu {
     pub fn new(
-        bootrom: Vec<u8>,
+        bootrom: Option<Vec<u8>>,
         cart: Box<Cartridge>,
         joypad: Joypad,
         timer: Timer,
@@ -43,8 +75,8 @@ impl Mmu {
         let mmu = Mmu {
             bootrom,
             cart,
-            vram: [0; 8 * 1024],
-            work_ram: [0; 8 * 1024],
+            vram: [0; VRAM_SIZE],
+            work_ram: [0; WORK_RAM_SIZE],
             oam: [0; 160],
             unhandled_io: [0; 128],
             zero_ram: [0; 128],
@@ -53,9 +85,24 @@ impl Mmu {
             timer: timer,
             joypad: joypad,
             ppu: ppu,
-            dma_cycles_left: 0,
+            cheats: Cheats::new(),
+            sgb: None,
+            frame_ready: false,
+            cgb_mode: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            vram_bank: 0,
+            work_ram_bank: 0,
+            ppu_cycle_skipped: false,
             dma_src: 0,
-            restrict_vram_oam: false,
+            dma_request: None,
+            dma_active: None,
+            hdma_src: 0,
+            hdma_dst: 0x8000,
+            hdma_blocks_left: 0,
+            hdma_active: false,
+            hdma_hblank: false,
+            hdma_block_bytes: 0,
         };
 
         mmu
@@ -68,62 +115,74 @@ impl Mmu {
         //        println!("Reading {:04X} from {:04X}", val, addr);
         val
     }
+
+    // the PPU locks VRAM while drawing a line, reads return 0xFF and writes are ignored
+    fn vram_accessible(&self) -> bool {
+        !self.ppu.lcdc.lcd_display_enable || self.ppu.mode != GpuMode::VramAccess
+    }
+
+    // OAM is also locked during the OAM search
+    fn oam_accessible(&self) -> bool {
+        let mode = self.ppu.mode;
+        !self.ppu.lcdc.lcd_display_enable || mode != GpuMode::VramAccess && mode != GpuMode::OamAccess
+    }
+
     pub fn read_byte(&self, addr: u16) -> u8 {
-        let val = if addr < 0x100 && self.bootrom_enabled() {
-            self.bootrom[addr as usize]
-        } else {
-            let addr = addr as usize;
-            match addr {
-                0x0000...0x3FFF => self.cart.read_byte(addr as u16),
-                0x4000...0x7FFF => self.cart.read_byte(addr as u16),
-                0x8000...0x9FFF => {
-                    if !self.restrict_vram_oam || self.ppu.mode != GpuMode::VramAccess {
-                        self.vram[addr - 0x8000]
-                    } else { 0xFF }
-                },
-                0xA000...0xBFFF => self.cart.read_byte(addr as u16),
-                0xC000...0xDFFF => self.work_ram[addr - 0xC000],
-                0xE000...0xFDFF => self.work_ram[addr - 0xE000],
-                0xFE00...0xFE9F => {
-                    let mode = self.ppu.mode;
-                    if !self.restrict_vram_oam || mode != GpuMode::VramAccess && mode != GpuMode::OamAccess {
-                        self.oam[addr - 0xFE00]
-                    } else { 0xFF }
-                },
-
-                0xFF00          => self.joypad.read_byte(),
-                0xFF04          => {
-                    eprintln!("self.timer.div() = {:?}", self.timer.div());
-                    self.timer.div()
-                },
-                0xFF05          => self.timer.tima(),
-                0xFF06          => self.timer.tma,
-                0xFF07          => self.timer.tac.to_u8(),
-
-                0xFFFF          => self.ie.bits(),
-                0xFF0F          => self._if.bits(),
-
-                // PPU
-                0xFF40          => self.ppu.lcdc.to_byte(),
-                0xFF41          => self.ppu.read_lcdstat(),
-                0xFF42          => self.ppu.sc_y,
-                0xFF43          => self.ppu.sc_x,
-                0xFF44          => self.ppu.ly,
-                0xFF45          => self.ppu.lyc,
-                0xFF46          => self.dma_src,
-                0xFF47          => self.ppu.bg_palette.to_u8(),
-                0xFF48          => self.ppu.obj0_palette.to_u8(),
-                0xFF49          => self.ppu.obj1_palette.to_u8(),
-                0xFF4A          => self.ppu.w_y,
-                0xFF4B          => self.ppu.w_x,
-
-                0xFF00...0xFF7F => self.unhandled_io[addr - 0xFF00],
-                0xFF80...0xFFFF => self.zero_ram[addr - 0xFF80],
-                0xFEA0...0xFEFF => 0, // accessing this memory is undefined behaviour
-                _               => panic!("Unhandled address in memory map: {:X}", addr)
-            }
-        };
-        val
+        if let Some(val) = self.read_bootrom(addr) {
+            return val;
+        }
+        match addr {
+            0x0000...0x3FFF => self.read_rom(addr),
+            0x4000...0x7FFF => self.read_rom(addr),
+            0x8000...0x9FFF => {
+                if self.vram_accessible() {
+                    self.vram[self.vram_offset(addr)]
+                } else { 0xFF }
+            },
+            0xA000...0xBFFF => self.cart.read_byte(addr),
+            0xC000...0xDFFF => self.work_ram[self.work_ram_offset(addr)],
+            0xE000...0xFDFF => self.work_ram[self.work_ram_offset(addr - 0x2000)],
+            0xFE00...0xFE9F => {
+                if self.oam_accessible() {
+                    self.oam[(addr - 0xFE00) as usize]
+                } else { 0xFF }
+            },
+
+            0xFF00          => self.joypad.read_byte(),
+            0xFF04          => self.timer.div(),
+            0xFF05          => self.timer.tima(),
+            0xFF06          => self.timer.tma(),
+            0xFF07          => self.timer.tac(),
+
+            0xFFFF          => self.ie.bits(),
+            0xFF0F          => 0xE0 | self._if.bits(), // unused bits read as 1
+
+            // PPU
+            0xFF40          => self.ppu.lcdc.to_byte(),
+            0xFF41          => self.ppu.read_lcdstat(),
+            0xFF42          => self.ppu.sc_y,
+            0xFF43          => self.ppu.sc_x,
+            0xFF44          => self.ppu.ly,
+            0xFF45          => self.ppu.lyc,
+            0xFF46          => self.dma_src,
+            0xFF47          => self.ppu.bg_palette.to_u8(),
+            0xFF48          => self.ppu.obj0_palette.to_u8(),
+            0xFF49          => self.ppu.obj1_palette.to_u8(),
+            0xFF4A          => self.ppu.w_y,
+            0xFF4B          => self.ppu.w_x,
+            0xFF4D          => self.read_key1(),
+            0xFF4F          => self.read_cgb_register(0xFE | self.vram_bank),
+            0xFF55          => self.read_cgb_register(self.read_hdma5()),
+            0xFF68          => self.read_cgb_register(self.ppu.bg_color_palettes.read_index()),
+            0xFF69          => self.read_cgb_register(self.ppu.bg_color_palettes.read_data()),
+            0xFF6A          => self.read_cgb_register(self.ppu.obj_color_palettes.read_index()),
+            0xFF6B          => self.read_cgb_register(self.ppu.obj_color_palettes.read_data()),
+            0xFF70          => self.read_cgb_register(0xF8 | self.work_ram_bank),
+
+            0xFF00...0xFF7F => self.unhandled_io[(addr - 0xFF00) as usize],
+            0xFF80...0xFFFF => self.zero_ram[(addr - 0xFF80) as usize],
+            0xFEA0...0xFEFF => 0, // accessing this memory is undefined behaviour
+        }
     }
 
     pub fn write_word(&mut self, val: u16, addr: u16) -> () {
@@ -133,39 +192,47 @@ impl Mmu {
     }
 
     pub fn write_byte(&mut self, val: u8, addr: u16) -> () {
-        let addr = addr as usize;
         match addr {
-            0x0000...0x3FFF => self.cart.write_byte(addr as u16, val),
-            0x4000...0x7FFF => self.cart.write_byte(addr as u16, val),
+            0x0000...0x3FFF => self.cart.write_byte(addr, val),
+            0x4000...0x7FFF => self.cart.write_byte(addr, val),
             0x8000...0x9FFF => {
-                if !self.restrict_vram_oam || self.ppu.mode != GpuMode::VramAccess {
-                    self.vram[addr - 0x8000] = val
+                if self.vram_accessible() {
+                    self.vram[self.vram_offset(addr)] = val
                 }
             },
-            0xA000...0xBFFF => self.cart.write_byte(addr as u16, val),
-            0xC000...0xDFFF => self.work_ram[addr - 0xC000] = val,
-            0xE000...0xFDFF => self.work_ram[addr - 0xE000] = val,
+            0xA000...0xBFFF => self.cart.write_byte(addr, val),
+            0xC000...0xDFFF => self.work_ram[self.work_ram_offset(addr)] = val,
+            0xE000...0xFDFF => self.work_ram[self.work_ram_offset(addr - 0x2000)] = val,
             0xFE00...0xFE9F => {
-                let mode = self.ppu.mode;
-                if !self.restrict_vram_oam || mode != GpuMode::VramAccess && mode != GpuMode::OamAccess {
-                    self.oam[addr - 0xFE00] = val
+                if self.oam_accessible() {
+                    self.oam[(addr - 0xFE00) as usize] = val
+                }
+            },
+            0xFF00          => {
+                self.joypad.write_byte(val);
+                if let (Some(command), Some(sgb)) = (self.joypad.take_sgb_command(), self.sgb.as_mut()) {
+                    sgb.handle_command(&command, &self.ppu.framebuffer);
                 }
             },
-            0xFF00          => self.joypad.write_byte(val),
             0xFF04          => self.timer.reset_div(),
             0xFF05          => self.timer.set_tima(val),
-            0xFF06          => self.timer.tma = val,
-            0xFF07          => self.timer.tac = TimerControl::from_u8(val),
+            0xFF06          => self.timer.set_tma(val),
+            0xFF07          => self.timer.set_tac(val),
             0xFFFF          => self.ie = Interrupts::from_bits_truncate(val),
             0xFF0F          => self._if = Interrupts::from_bits_truncate(val),
 
 
             // PPU
-            0xFF40          => self.ppu.lcdc = Lcdc::from_byte(val),
-            0xFF41          => self.ppu.write_lcdstat(val),
+            0xFF40          => self.ppu.write_lcdc(val),
+            0xFF41          => {
+                if !self.cgb_mode && self.ppu.stat_write_glitch() {
+                    self._if |= Interrupts::LCD_STAT;
+                }
+                self.ppu.write_lcdstat(val)
+            }
             0xFF42          => self.ppu.sc_y = val ,
             0xFF43          => self.ppu.sc_x = val,
-            0xFF44          => self.ppu.ly = val,
+            0xFF44          => {}   // LY is read only
             0xFF45          => self.ppu.lyc = val,
             0xFF46          => self.dma(val),   // init DMA transfer
             0xFF47          => self.ppu.bg_palette = DmgPalette::from_u8(val),
@@ -173,25 +240,234 @@ impl Mmu {
             0xFF49          => self.ppu.obj1_palette = DmgPalette::from_u8(val),
             0xFF4A          => self.ppu.w_y = val,
             0xFF4B          => self.ppu.w_x = val,
+            0xFF4D          => self.speed_switch_armed = val & 1 != 0,
+            0xFF4F          => if self.cgb_mode { self.vram_bank = val & 1 },
+            0xFF51..=0xFF54 => if self.cgb_mode { self.write_hdma_address(addr, val) },
+            0xFF55          => if self.cgb_mode { self.write_hdma5(val) },
+            0xFF68          => if self.cgb_mode { self.ppu.bg_color_palettes.write_index(val) },
+            0xFF69          => if self.cgb_mode { self.ppu.bg_color_palettes.write_data(val) },
+            0xFF6A          => if self.cgb_mode { self.ppu.obj_color_palettes.write_index(val) },
+            0xFF6B          => if self.cgb_mode { self.ppu.obj_color_palettes.write_data(val) },
+            0xFF70          => if self.cgb_mode { self.work_ram_bank = val & 0b111 },
 
-            0xFF01...0xFF7F => self.unhandled_io[addr - 0xFF00] = val,
-            0xFF80...0xFFFF => self.zero_ram[addr - 0xFF80] = val,
+            0xFF01...0xFF7F => self.unhandled_io[(addr - 0xFF00) as usize] = val,
+            0xFF80...0xFFFF => self.zero_ram[(addr - 0xFF80) as usize] = val,
             0xFEA0...0xFEFF => {}, // accessing this memory is undefined behaviour
-            _ => panic!("Unhandled address in memory map: {:X}", addr),
         }
     }
 
+    fn read_rom(&self, addr: u16) -> u8 {
+        let val = self.cart.read_byte(addr);
+        self.cheats.patch_rom(addr, val)
+    }
+
+    pub fn apply_ram_cheats(&mut self) {
+        for (addr, val, bank) in self.cheats.ram_writes() {
+            match (addr, bank) {
+                (0xA000..=0xBFFF, Some(bank)) => self.cart.write_ram_bank(bank, addr, val),
+                (0xD000..=0xDFFF, Some(bank)) if self.cgb_mode => {
+                    let bank = (bank as usize & 7).max(1);
+                    self.work_ram[bank * WORK_RAM_BANK_SIZE + (addr - 0xD000) as usize] = val;
+                }
+                _ => self.write_byte(val, addr),
+            }
+        }
+    }
+
+    /// Advances everything on the bus but the CPU by one machine cycle
+    pub fn tick(&mut self) {
+        self.tick_dma();
+        self.tick_vram_dma();
+
+        if self.double_speed {
+            self.ppu_cycle_skipped = !self.ppu_cycle_skipped;
+        }
+        if !self.ppu_cycle_skipped {
+            self.tick_ppu();
+        }
+
+        let timer_interrupt = self.timer.pass_time(1);
+        if timer_interrupt { self._if |= Interrupts::TIMER }
+    }
+
+    fn tick_ppu(&mut self) {
+        let prev_mode = self.ppu.mode;
+        let (vblank_int, stat_int) = self.ppu.step(&self.vram, &self.oam);
+        let hblank_started = prev_mode != GpuMode::HBlank && self.ppu.mode == GpuMode::HBlank;
+        if hblank_started && self.ppu.ly < 144 && self.hdma_active && self.hdma_hblank {
+            self.hdma_block_bytes = HDMA_BLOCK_SIZE;
+        }
+        if vblank_int.is_some() {
+            self._if |= Interrupts::VBLANK;
+            self.apply_ram_cheats();
+        }
+        if self.ppu.take_frame_done() {
+            self.frame_ready = true;
+        }
+        if stat_int.is_some() { self._if |= Interrupts::LCD_STAT }
+    }
+
+    // CGB only registers read as FF on DMG
+    fn read_cgb_register(&self, val: u8) -> u8 {
+        if self.cgb_mode { val } else { 0xFF }
+    }
+
+    fn vram_offset(&self, addr: u16) -> usize {
+        self.vram_bank as usize * VRAM_BANK_SIZE + (addr - 0x8000) as usize
+    }
+
+    // C000-CFFF is always bank 0, D000-DFFF is bank 1-7
+    fn work_ram_offset(&self, addr: u16) -> usize {
+        let offset = (addr - 0xC000) as usize;
+        if offset < WORK_RAM_BANK_SIZE {
+            offset
+        } else {
+            let bank = self.work_ram_bank.max(1) as usize;
+            bank * WORK_RAM_BANK_SIZE + offset - WORK_RAM_BANK_SIZE
+        }
+    }
+
+    // KEY1 - CGB speed switch, bit 7 is the current speed, bit 0 arms the switch
+    fn read_key1(&self) -> u8 {
+        if !self.cgb_mode {
+            return 0xFF;
+        }
+        0x7E | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8
+    }
+
+    pub fn speed_switch_armed(&self) -> bool {
+        self.cgb_mode && self.speed_switch_armed
+    }
+
+    /// Done by STOP when KEY1 is armed
+    pub fn switch_speed(&mut self) {
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        self.ppu_cycle_skipped = false;
+    }
+
     fn dma(&mut self, src: u8) {
-        assert!(src <= 0xF1);
+        const DMA_STARTUP_CYCLES: u8 = 1;
         self.dma_src = src;
-        let src_from = (src as u16) << 8;
-        for i in 0x00..0xA0 {
-            self.oam[i] = self.read_byte(src_from + i as u16);
+        // a transfer that is already running goes on until the new one starts
+        self.dma_request = Some(((src as u16) << 8, DMA_STARTUP_CYCLES));
+    }
+
+    /// Copies one byte per machine cycle, 160 cycles in total
+    fn tick_dma(&mut self) {
+        match self.dma_request {
+            Some((src, 0)) => {
+                self.dma_request = None;
+                self.dma_active = Some((src, 0));
+            }
+            Some((src, delay)) => self.dma_request = Some((src, delay - 1)),
+            None => {}
+        }
+        if let Some((src, idx)) = self.dma_active {
+            let addr = src + idx as u16;
+            // E000-FFFF can't be used as a source, the transfer reads from work RAM instead
+            let addr = if addr >= 0xE000 { addr - 0x2000 } else { addr };
+            self.oam[idx as usize] = self.read_byte(addr);
+            self.dma_active = if idx < OAM_SIZE as u8 - 1 { Some((src, idx + 1)) } else { None };
+        }
+    }
+
+    pub fn dma_running(&self) -> bool {
+        self.dma_active.is_some()
+    }
+
+    // HDMA1-4 are write only, the low 4 bits of both addresses are ignored
+    fn write_hdma_address(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF51 => self.hdma_src = (val as u16) << 8 | self.hdma_src & 0x00F0,
+            0xFF52 => self.hdma_src = self.hdma_src & 0xFF00 | (val & 0xF0) as u16,
+            // the destination is always in VRAM
+            0xFF53 => self.hdma_dst = 0x8000 | ((val & 0x1F) as u16) << 8 | self.hdma_dst & 0x00F0,
+            _ => self.hdma_dst = self.hdma_dst & 0xFF00 | (val & 0xF0) as u16,
+        }
+    }
+
+    // bit 7 clear while a transfer is active, the rest is the number of blocks left minus 1
+    fn read_hdma5(&self) -> u8 {
+        (!self.hdma_active as u8) << 7 | self.hdma_blocks_left.wrapping_sub(1) & 0x7F
+    }
+
+    fn write_hdma5(&mut self, val: u8) {
+        if self.hdma_active && self.hdma_hblank && val & 0x80 == 0 {
+            // stops an HBlank transfer, the block being copied still finishes
+            self.hdma_active = false;
+            return;
+        }
+        self.hdma_blocks_left = (val & 0x7F) + 1;
+        self.hdma_hblank = val & 0x80 != 0;
+        self.hdma_active = true;
+        // a general purpose transfer starts right away, an HBlank one also does while the LCD is off
+        if !self.hdma_hblank || !self.ppu.lcdc.lcd_display_enable {
+            self.hdma_block_bytes = HDMA_BLOCK_SIZE;
+        }
+    }
+
+    /// Copies 16 bytes per 8 machine cycles in normal speed, per 16 in double speed
+    fn tick_vram_dma(&mut self) {
+        if self.hdma_block_bytes == 0 {
+            return;
+        }
+        let bytes_per_cycle = if self.double_speed { 1 } else { 2 };
+        for _ in 0..bytes_per_cycle {
+            let val = self.read_byte(self.hdma_src);
+            let dst = self.vram_offset(self.hdma_dst);
+            self.vram[dst] = val;
+            self.hdma_src = self.hdma_src.wrapping_add(1);
+            self.hdma_dst = 0x8000 | (self.hdma_dst + 1) & 0x1FFF;
+            self.hdma_block_bytes -= 1;
+        }
+        if self.hdma_block_bytes == 0 {
+            self.hdma_blocks_left -= 1;
+            if self.hdma_blocks_left == 0 {
+                self.hdma_active = false;
+            } else if !self.hdma_hblank {
+                self.hdma_block_bytes = HDMA_BLOCK_SIZE;
+            }
+        }
+    }
+
+    /// The CPU doesn't run while the VRAM DMA copies a block
+    pub fn vram_dma_running(&self) -> bool {
+        self.hdma_block_bytes > 0
+    }
+
+    // During OAM DMA the CPU can only use the IO registers and HRAM,
+    // the rest of the bus (OAM included) reads as FF and ignores writes.
+    fn cpu_can_access(&self, addr: u16) -> bool {
+        !self.dma_running() || addr >= 0xFF00
+    }
+
+    pub fn cpu_read_byte(&self, addr: u16) -> u8 {
+        if self.cpu_can_access(addr) { self.read_byte(addr) } else { 0xFF }
+    }
+
+    pub fn cpu_write_byte(&mut self, val: u8, addr: u16) {
+        if self.cpu_can_access(addr) {
+            self.write_byte(val, addr)
         }
     }
 
     fn bootrom_enabled(&self) -> bool {
-        self.read_byte(0xFF50) == 0
+        self.bootrom.is_some() && self.unhandled_io[0x50] == 0
+    }
+
+    // CGB bootrom is mapped to 0000-00FF and 0200-08FF, the cartridge header stays visible
+    fn read_bootrom(&self, addr: u16) -> Option<u8> {
+        if !self.bootrom_enabled() {
+            return None;
+        }
+        let bootrom = self.bootrom.as_ref()?;
+        let mapped = addr < 0x100 || (0x200..0x900).contains(&addr);
+        if mapped {
+            bootrom.get(addr as usize).cloned()
+        } else {
+            None
+        }
     }
 
     fn read_vram(&self, addr: u16) {
@@ -1,3 +1,4 @@
+use crate::error::EmuError;
 use crate::gb::cpu::Cpu;
 use crate::gb::cpu::Reg8;
 
@@ -8,47 +9,76 @@ pub mod mmu;
 pub mod timer;
 pub mod ppu;
 pub mod mbc;
+pub mod cheats;
+pub mod compat_palettes;
+pub mod sgb;
+pub mod model;
+pub mod ram_search;
 
 pub struct Gb {
-    pub cpu: Cpu
+    pub cpu: Cpu,
+    // if false, invalid opcodes only lock up the CPU, as on real hardware
+    pub stop_on_invalid_opcode: bool,
+    // while the CPU is stopped nothing gets drawn, but frames are still paced
+    stopped_cycles: u32,
 }
 
+const CYCLES_PER_FRAME: u32 = 17556;
+
 impl Gb {
     pub fn new(cpu: Cpu) -> Gb {
-        Gb { cpu }
+        Gb {
+            cpu,
+            stop_on_invalid_opcode: false,
+            stopped_cycles: 0,
+        }
     }
 
-    pub fn run_machine_cycle(&mut self, _debug_mode: bool) -> bool {
+    /// Runs one instruction, an interrupt dispatch, or a single machine cycle
+    /// while the CPU is halted or locked up. The rest of the machine is ticked
+    /// along with every memory access.
+    /// Returns true if a frame is ready to be drawn
+    pub fn step(&mut self) -> Result<bool, EmuError> {
         let cpu = &mut self.cpu;
+        let mut invalid_opcode = None;
+
+        if cpu.stopped {
+            if !cpu.mmu.joypad.any_selected_pressed() {
+                // the clock is stopped, nothing else on the bus advances
+                self.stopped_cycles += 1;
+                if self.stopped_cycles == CYCLES_PER_FRAME {
+                    self.stopped_cycles = 0;
+                    return Ok(true);
+                }
+                return Ok(false);
+            }
+            cpu.stopped = false;
+        }
 
         if cpu.halted && cpu.any_interrupt() {
             cpu.halted = false;
         }
 
-        if !cpu.halted && !cpu.is_busy() {
+        if cpu.mmu.vram_dma_running() || cpu.halted || cpu.locked_up {
+            cpu.idle_cycle();
+        } else {
             let interrupt_handled = cpu.handle_interrupts();
             cpu.handle_ei_delay();
-            if interrupt_handled {
-                let interrupt_routine_cycles = 5;
-                cpu.set_busy(interrupt_routine_cycles);
-            } else {
+            if !interrupt_handled {
                 let opcode = cpu.fetch_opcode_byte();
-                let instr_cycles = execute(cpu, opcode);
-                cpu.set_busy(instr_cycles as u32);
+                execute(cpu, opcode);
+                if cpu.locked_up {
+                    let addr = cpu.pc.wrapping_sub(1);
+                    invalid_opcode = Some(EmuError::InvalidOpcode { opcode, addr });
+                }
             }
-            cpu.handle_ei_delay();
         }
 
-        let (vblank_int, stat_int) = cpu.mmu.ppu.step(&cpu.mmu.vram, &cpu.mmu.oam);
-        if vblank_int.is_some() { cpu.mmu._if |= Interrupts::VBLANK }
-        if stat_int.is_some() { cpu.mmu._if |= Interrupts::LCD_STAT }
-
-        let timer_interrupt = cpu.mmu.timer.pass_time(1);
-        if timer_interrupt { cpu.mmu._if |= Interrupts::TIMER }
-
-        if !cpu.halted { cpu.pass_cycle() }
-
-        return vblank_int.is_some();
+        let frame_ready = std::mem::replace(&mut cpu.mmu.frame_ready, false);
+        match invalid_opcode {
+            Some(e) if self.stop_on_invalid_opcode => Err(e),
+            _ => Ok(frame_ready),
+        }
     }
 
 }
@@ -67,11 +97,14 @@ bitflags! {
 }
 
 
+// Machine cycles taken by each instruction. Conditional jumps, calls and returns
+// are listed with the cost of the branch not being taken, their handlers return
+// the actual cost.
 static CYCLES: [u8; 256] = [
     1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
     1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
-    3, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
-    3, 3, 2, 2, 3, 3, 3, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
@@ -80,8 +113,8 @@ static CYCLES: [u8; 256] = [
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
     1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
-    5, 3, 4, 4, 6, 4, 2, 4, 5, 4, 4, 0, 6, 6, 2, 4,
-    5, 3, 4, 4, 6, 4, 2, 4, 5, 4, 4, 0, 6, 0, 2, 4,
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4,
+    2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4,
     3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4,
     3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
 ];
@@ -134,7 +167,7 @@ fn execute(cpu: &mut Cpu, opcode: u8) -> u8 {
         0x15 => cpu.DEC(D),
         0x16 => cpu.LD_rn(D),
         0x17 => cpu.RLA(),
-        0x18 => cpu.JR(),
+        0x18 => return cpu.JR(),
         0x19 => cpu.ADD_HL_DE(),
         0x1A => cpu.ld_a_de(),
         0x1B => cpu.DEC_DE(),
@@ -143,7 +176,7 @@ fn execute(cpu: &mut Cpu, opcode: u8) -> u8 {
         0x1E => cpu.LD_rn(E),
         0x1F => cpu.RRA(),
 
-        0x20 => cpu.JR_NZ(),
+        0x20 => return cpu.JR_NZ(),
         0x21 => cpu.ld_hl_nn(),
         0x22 => cpu.ldi_hl_a(),
         0x23 => cpu.INC_HL(),
@@ -151,7 +184,7 @@ fn execute(cpu: &mut Cpu, opcode: u8) -> u8 {
         0x25 => cpu.DEC(H),
         0x26 => cpu.LD_rn(H),
         0x27 => cpu.daa(),
-        0x28 => cpu.JR_Z(),
+        0x28 => return cpu.JR_Z(),
         0x29 => cpu.ADD_HL_HL(),
         0x2A => cpu.ldi_a_hl(),
         0x2B => cpu.DEC_HL(),
@@ -160,7 +193,7 @@ fn execute(cpu: &mut Cpu, opcode: u8) -> u8 {
         0x2E => cpu.LD_rn(L),
         0x2F => cpu.cpl(),
 
-        0x30 => cpu.JR_NC(),
+        0x30 => return cpu.JR_NC(),
         0x31 => cpu.ld_sp_nn(),
         0x32 => cpu.ldd_hl_a(),
         0x33 => cpu.INC_SP(),
@@ -168,7 +201,7 @@ fn execute(cpu: &mut Cpu, opcode: u8) -> u8 {
         0x35 => cpu.DEC_aHL(),
         0x36 => cpu.ld__hl__n(),
         0x37 => cpu.scf(),
-        0x38 => cpu.JR_C(),
+        0x38 => return cpu.JR_C(),
         0x39 => cpu.ADD_HL_SP(),
         0x3A => cpu.ldd_a_hl(),
         0x3B => cpu.DEC_SP(),
@@ -313,56 +346,56 @@ fn execute(cpu: &mut Cpu, opcode: u8) -> u8 {
         0xBE => cpu.CP_HL(),
         0xBF => cpu.CP(A),
 
-        0xC0 => cpu.RET_NZ(),
+        0xC0 => return cpu.RET_NZ(),
         0xC1 => cpu.pop_bc(),
-        0xC2 => cpu.JP_NZ(),
-        0xC3 => cpu.JP(),
-        0xC4 => cpu.CALL_NZ(),
+        0xC2 => return cpu.JP_NZ(),
+        0xC3 => return cpu.JP(),
+        0xC4 => return cpu.CALL_NZ(),
         0xC5 => cpu.push_bc(),
         0xC6 => cpu.ADD_n(),
         0xC7 => cpu.RST_00H(),
-        0xC8 => cpu.RET_Z(),
-        0xC9 => cpu.RET(),
-        0xCA => cpu.JP_Z(),
+        0xC8 => return cpu.RET_Z(),
+        0xC9 => return cpu.RET(),
+        0xCA => return cpu.JP_Z(),
         0xCB => {
             return execute_CB_prefixed(cpu);
         }
-        0xCC => cpu.CALL_Z(),
-        0xCD => cpu.CALL(),
+        0xCC => return cpu.CALL_Z(),
+        0xCD => return cpu.CALL(),
         0xCE => cpu.ADC_n(),
         0xCF => cpu.RST_08H(),
 
-        0xD0 => cpu.RET_NC(),
+        0xD0 => return cpu.RET_NC(),
         0xD1 => cpu.pop_de(),
-        0xD2 => cpu.JP_NC(),
-        0xD3 => handle_invalid_opcode(opcode),
-        0xD4 => cpu.CALL_NC(),
+        0xD2 => return cpu.JP_NC(),
+        0xD3 => cpu.lock_up(),
+        0xD4 => return cpu.CALL_NC(),
         0xD5 => cpu.push_de(),
         0xD6 => cpu.SUB_n(),
         0xD7 => cpu.RST_10H(),
-        0xD8 => cpu.RET_C(),
-        0xD9 => cpu.RETI(),
-        0xDA => cpu.JP_C(),
-        0xDB => handle_invalid_opcode(opcode),
-        0xDC => cpu.CALL_C(),
-        0xDD => handle_invalid_opcode(opcode),
+        0xD8 => return cpu.RET_C(),
+        0xD9 => return cpu.RETI(),
+        0xDA => return cpu.JP_C(),
+        0xDB => cpu.lock_up(),
+        0xDC => return cpu.CALL_C(),
+        0xDD => cpu.lock_up(),
         0xDE => cpu.SBC_n(),
         0xDF => cpu.RST_18H(),
 
         0xE0 => cpu.ldh_n_a(),
         0xE1 => cpu.pop_hl(),
         0xE2 => cpu.ld__c__a(),
-        0xE3 => handle_invalid_opcode(opcode),
-        0xE4 => handle_invalid_opcode(opcode),
+        0xE3 => cpu.lock_up(),
+        0xE4 => cpu.lock_up(),
         0xE5 => cpu.push_hl(),
         0xE6 => cpu.AND_n(),
         0xE7 => cpu.RST_20H(),
         0xE8 => cpu.ADD_SP_n(),
         0xE9 => cpu.JP_aHL(),
         0xEA => cpu.ld_nn_a(),
-        0xEB => handle_invalid_opcode(opcode),
-        0xEC => handle_invalid_opcode(opcode),
-        0xED => handle_invalid_opcode(opcode),
+        0xEB => cpu.lock_up(),
+        0xEC => cpu.lock_up(),
+        0xED => cpu.lock_up(),
         0xEE => cpu.XOR_n(),
         0xEF => cpu.RST_28H(),
 
@@ -370,7 +403,7 @@ fn execute(cpu: &mut Cpu, opcode: u8) -> u8 {
         0xF1 => cpu.pop_af(),
         0xF2 => cpu.ld_a__c_(),
         0xF3 => cpu.di(),
-        0xF4 => handle_invalid_opcode(opcode),
+        0xF4 => cpu.lock_up(),
         0xF5 => cpu.push_af(),
         0xF6 => cpu.OR_n(),
         0xF7 => cpu.RST_30H(),
@@ -378,27 +411,19 @@ fn execute(cpu: &mut Cpu, opcode: u8) -> u8 {
         0xF9 => cpu.ld_sp_hl(),
         0xFA => cpu.ld_a_nn(),
         0xFB => cpu.ei(),
-        0xFC => {
-            eprintln!("{:02x}", cpu.pc);
-            handle_invalid_opcode(opcode)
-        }
-        0xFD => handle_invalid_opcode(opcode),
+        0xFC => cpu.lock_up(),
+        0xFD => cpu.lock_up(),
         0xFE => cpu.CP_n(),
         0xFF => cpu.RST_38H(),
-        _ => handle_invalid_opcode(opcode),
+        _ => cpu.lock_up(),
     }
     return CYCLES[opcode as usize];
 }
 
-fn handle_invalid_opcode(opcode: u8) {
-    panic!("INVALID OPCODE {:02x}", opcode)
-}
-
 
 pub fn execute_CB_prefixed(cpu: &mut Cpu) -> u8 {
     const OPERATION_MASK: u8 = 0b1111_1000;
-    let opcode = cpu.mmu.read_byte(cpu.pc);
-    cpu.pc += 1;
+    let opcode = cpu.fetch_opcode_byte();
     let reg_code = reg_code(opcode);
 
     match opcode & OPERATION_MASK {
@@ -481,3 +506,590 @@ pub enum RegOrHl {
     Reg(Reg8),
     HL,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gb::joypad::Joypad;
+    use crate::gb::mbc::Mbc1;
+    use crate::gb::mbc::NoMbc;
+    use crate::gb::mmu::Mmu;
+    use crate::gb::ppu::GpuMode;
+    use crate::gb::ppu::Ppu;
+    use crate::gb::timer::Timer;
+
+    /// Gb without a bootrom and with the program placed at 0x100
+    pub fn gb_with_program(program: &[u8]) -> Gb {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        let cart = Box::new(NoMbc::new(rom));
+        let mmu = Mmu::new(None, cart, Joypad::new(), Timer::new(), Ppu::new());
+        let mut cpu = Cpu::new(mmu);
+        cpu.pc = 0x100;
+        cpu.sp = 0xFFFE;
+        Gb::new(cpu)
+    }
+
+    #[test]
+    fn game_shark_writes_named_ram_bank() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 1;
+        let cart = Box::new(Mbc1::new(rom));
+        let mut mmu = Mmu::new(None, cart, Joypad::new(), Timer::new(), Ppu::new());
+        assert!(mmu.cheats.add("822A00A0", "", true));
+        assert!(mmu.cheats.add("0133C0C0", "", true));
+        // bank 0 is mapped, the code names bank 2
+        mmu.apply_ram_cheats();
+        assert_eq!(mmu.read_byte(0xA000), 0x00);
+        assert_eq!(mmu.read_byte(0xC0C0), 0x33);
+        mmu.write_byte(0x01, 0x6000);
+        mmu.write_byte(0x02, 0x4000);
+        assert_eq!(mmu.read_byte(0xA000), 0x2A);
+    }
+
+    #[test]
+    fn invalid_opcode_locks_up_cpu() {
+        let mut gb = gb_with_program(&[0x00, 0xD3, 0x00]);
+        for _ in 0..100 {
+            assert!(gb.step().is_ok());
+        }
+        assert!(gb.cpu.locked_up);
+        assert_eq!(gb.cpu.pc, 0x102);
+    }
+
+    #[test]
+    fn invalid_opcode_is_reported() {
+        let mut gb = gb_with_program(&[0x00, 0xD3, 0x00]);
+        gb.stop_on_invalid_opcode = true;
+        assert!(gb.step().is_ok());
+        match gb.step() {
+            Err(EmuError::InvalidOpcode { opcode: 0xD3, addr: 0x101 }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    fn instr_cycles(program: &[u8], flags: u8) -> u8 {
+        let mut gb = gb_with_program(program);
+        gb.cpu.f = flags;
+        let opcode = gb.cpu.fetch_opcode_byte();
+        execute(&mut gb.cpu, opcode)
+    }
+
+    #[test]
+    fn conditional_branch_cycles_test() {
+        const Z: u8 = 0x80;
+        const C: u8 = 0x10;
+        assert_eq!(instr_cycles(&[0x20, 0x05], 0), 3); // JR NZ
+        assert_eq!(instr_cycles(&[0x20, 0x05], Z), 2);
+        assert_eq!(instr_cycles(&[0xCA, 0x00, 0x02], Z), 4); // JP Z
+        assert_eq!(instr_cycles(&[0xCA, 0x00, 0x02], 0), 3);
+        assert_eq!(instr_cycles(&[0xDC, 0x00, 0x02], C), 6); // CALL C
+        assert_eq!(instr_cycles(&[0xDC, 0x00, 0x02], 0), 3);
+        assert_eq!(instr_cycles(&[0xD0], 0), 5); // RET NC
+        assert_eq!(instr_cycles(&[0xD0], C), 2);
+        assert_eq!(instr_cycles(&[0xC9], 0), 4); // RET
+        assert_eq!(instr_cycles(&[0xCD, 0x00, 0x02], 0), 6); // CALL
+    }
+
+    #[test]
+    fn halt_waits_for_enabled_interrupt() {
+        // HALT, INC A
+        let mut gb = gb_with_program(&[0x76, 0x3C]);
+        gb.step().unwrap();
+        assert!(gb.cpu.halted);
+        gb.cpu.mmu._if = Interrupts::TIMER;
+        gb.step().unwrap();
+        assert!(gb.cpu.halted);
+
+        // with IME=0 the CPU resumes after HALT without dispatching the interrupt
+        gb.cpu.mmu.ie = Interrupts::TIMER;
+        gb.step().unwrap();
+        assert!(!gb.cpu.halted);
+        assert_eq!(gb.cpu.a, 1);
+        assert_eq!(gb.cpu.pc, 0x102);
+    }
+
+    #[test]
+    fn halt_dispatches_interrupt_with_ime() {
+        let mut gb = gb_with_program(&[0x76, 0x3C]);
+        gb.cpu.ime = true;
+        gb.cpu.mmu.ie = Interrupts::VBLANK;
+        gb.step().unwrap();
+        assert!(gb.cpu.halted);
+        gb.cpu.mmu._if = Interrupts::VBLANK;
+        gb.step().unwrap();
+        assert_eq!(gb.cpu.pc, 0x40);
+        assert_eq!(gb.cpu.mmu.read_word(0xFFFC), 0x101);
+    }
+
+    #[test]
+    fn halt_bug_reads_next_byte_twice() {
+        // HALT, INC A, LD B, A
+        let mut gb = gb_with_program(&[0x76, 0x3C, 0x47]);
+        gb.cpu.mmu.ie = Interrupts::TIMER;
+        gb.cpu.mmu._if = Interrupts::TIMER;
+        gb.step().unwrap();
+        assert!(!gb.cpu.halted);
+        gb.step().unwrap();
+        gb.step().unwrap();
+        assert_eq!(gb.cpu.a, 2);
+        assert_eq!(gb.cpu.pc, 0x102);
+    }
+
+    #[test]
+    fn stop_waits_for_joypad() {
+        use piston_window::Button;
+        use piston_window::Key;
+
+        // STOP, (skipped byte), INC A
+        let mut gb = gb_with_program(&[0x10, 0x00, 0x3C]);
+        gb.cpu.mmu.timer.set_div_internal(0x1234);
+        gb.cpu.mmu.ppu.framebuffer[0] = vram::DmgColor::Black;
+        gb.step().unwrap();
+        assert!(gb.cpu.stopped);
+        assert_eq!(gb.cpu.mmu.read_byte(0xFF04), 0);
+        assert_eq!(gb.cpu.mmu.ppu.framebuffer[0], vram::DmgColor::White);
+
+        let frames = (0..CYCLES_PER_FRAME).filter(|_| gb.step().unwrap()).count();
+        assert_eq!(frames, 1);
+        assert!(gb.cpu.stopped);
+        assert_eq!(gb.cpu.mmu.read_byte(0xFF04), 0);
+
+        gb.cpu.mmu.joypad.on_input(&Button::Keyboard(Key::Down), true);
+        gb.step().unwrap();
+        assert!(!gb.cpu.stopped);
+        assert_eq!(gb.cpu.a, 1);
+    }
+
+    #[test]
+    fn stop_switches_speed_when_armed() {
+        let mut gb = gb_with_program(&[0x10, 0x00, 0x3C]);
+        gb.cpu.mmu.cgb_mode = true;
+        assert_eq!(gb.cpu.mmu.read_byte(0xFF4D), 0x7E);
+        gb.cpu.mmu.write_byte(0x01, 0xFF4D);
+        assert_eq!(gb.cpu.mmu.read_byte(0xFF4D), 0x7F);
+        gb.step().unwrap();
+        assert!(!gb.cpu.stopped);
+        assert!(gb.cpu.mmu.double_speed);
+        assert_eq!(gb.cpu.mmu.read_byte(0xFF4D), 0xFE);
+
+        // KEY1 does nothing outside of CGB mode
+        let mut gb = gb_with_program(&[0x10, 0x00, 0x3C]);
+        gb.cpu.mmu.write_byte(0x01, 0xFF4D);
+        assert_eq!(gb.cpu.mmu.read_byte(0xFF4D), 0xFF);
+        gb.step().unwrap();
+        assert!(gb.cpu.stopped);
+        assert!(!gb.cpu.mmu.double_speed);
+    }
+
+    #[test]
+    fn ppu_locks_vram_and_oam() {
+        let mut gb = gb_with_program(&[0x00]);
+        let mmu = &mut gb.cpu.mmu;
+        mmu.vram[0] = 0x12;
+        mmu.oam[0] = 0x34;
+        mmu.write_byte(0x91, 0xFF40);
+        fn tick_until(mmu: &mut Mmu, mode: GpuMode) {
+            while mmu.ppu.mode != mode {
+                mmu.tick();
+            }
+        }
+
+        tick_until(mmu, GpuMode::OamAccess);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0xFE00), 0xFF);
+        mmu.write_byte(0x56, 0xFE00);
+        assert_eq!(mmu.oam[0], 0x34);
+
+        tick_until(mmu, GpuMode::VramAccess);
+        assert_eq!(mmu.read_byte(0x8000), 0xFF);
+        assert_eq!(mmu.read_byte(0xFE00), 0xFF);
+        mmu.write_byte(0x56, 0x8000);
+        assert_eq!(mmu.vram[0], 0x12);
+
+        tick_until(mmu, GpuMode::HBlank);
+        assert_eq!(mmu.read_byte(0x8000), 0x12);
+        assert_eq!(mmu.read_byte(0xFE00), 0x34);
+
+        // unlocked while the LCD is off
+        tick_until(mmu, GpuMode::VramAccess);
+        mmu.write_byte(0x11, 0xFF40);
+        mmu.write_byte(0x56, 0x8000);
+        mmu.write_byte(0x78, 0xFE00);
+        assert_eq!(mmu.read_byte(0x8000), 0x56);
+        assert_eq!(mmu.read_byte(0xFE00), 0x78);
+    }
+
+    // DIV and TIMA increments over 32 LCD lines, TIMA counting at DIV's rate
+    fn timer_ticks_over_lines(double_speed: bool) -> (u8, u8) {
+        let mut gb = gb_with_program(&[0x00]);
+        let mmu = &mut gb.cpu.mmu;
+        mmu.cgb_mode = true;
+        mmu.double_speed = double_speed;
+        mmu.write_byte(0x07, 0xFF07);
+        mmu.write_byte(0x91, 0xFF40);
+        while mmu.ppu.ly != 1 {
+            mmu.tick();
+        }
+        mmu.write_byte(0x00, 0xFF04);
+        mmu.write_byte(0x00, 0xFF05);
+        while mmu.ppu.ly != 33 {
+            mmu.tick();
+        }
+        (mmu.read_byte(0xFF04), mmu.read_byte(0xFF05))
+    }
+
+    #[test]
+    fn double_speed_runs_timer_twice_as_fast() {
+        assert_eq!(timer_ticks_over_lines(false), (57, 57));
+        assert_eq!(timer_ticks_over_lines(true), (114, 114));
+    }
+
+    #[test]
+    fn interrupt_dispatch_test() {
+        let mut gb = gb_with_program(&[0x00]);
+        gb.cpu.ime = true;
+        gb.cpu.mmu.ie = Interrupts::TIMER | Interrupts::VBLANK;
+        gb.cpu.mmu._if = Interrupts::TIMER | Interrupts::JOYPAD;
+        assert_eq!(gb.cpu.mmu.read_byte(0xFF0F), 0xF4);
+        gb.step().unwrap();
+        assert_eq!(gb.cpu.clock, 5);
+        assert_eq!(gb.cpu.pc, 0x50);
+        assert_eq!(gb.cpu.sp, 0xFFFC);
+        assert_eq!(gb.cpu.mmu.read_word(0xFFFC), 0x100);
+        assert_eq!(gb.cpu.mmu._if, Interrupts::JOYPAD);
+        assert!(!gb.cpu.ime);
+    }
+
+    #[test]
+    fn ie_push_cancels_interrupt() {
+        // pushing PC high byte 0x01 onto IE leaves only vblank enabled
+        let mut gb = gb_with_program(&[0x00]);
+        gb.cpu.ime = true;
+        gb.cpu.sp = 0x0000;
+        gb.cpu.mmu.ie = Interrupts::TIMER;
+        gb.cpu.mmu._if = Interrupts::TIMER;
+        gb.step().unwrap();
+        assert_eq!(gb.cpu.pc, 0x0000);
+        assert_eq!(gb.cpu.mmu._if, Interrupts::TIMER);
+
+        let mut gb = gb_with_program(&[0x00]);
+        gb.cpu.ime = true;
+        gb.cpu.sp = 0x0000;
+        gb.cpu.mmu.ie = Interrupts::TIMER;
+        gb.cpu.mmu._if = Interrupts::TIMER | Interrupts::VBLANK;
+        gb.step().unwrap();
+        assert_eq!(gb.cpu.pc, 0x0040);
+        assert_eq!(gb.cpu.mmu._if, Interrupts::TIMER);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_next_instruction() {
+        // EI, INC A, INC A
+        let mut gb = gb_with_program(&[0xFB, 0x3C, 0x3C]);
+        gb.cpu.mmu.ie = Interrupts::VBLANK;
+        gb.cpu.mmu._if = Interrupts::VBLANK;
+        gb.step().unwrap();
+        gb.step().unwrap();
+        assert_eq!(gb.cpu.a, 1);
+        gb.step().unwrap();
+        assert_eq!(gb.cpu.pc, 0x40);
+        assert_eq!(gb.cpu.a, 1);
+
+        // EI, DI: no interrupt window in between
+        let mut gb = gb_with_program(&[0xFB, 0xF3, 0x3C]);
+        gb.cpu.mmu.ie = Interrupts::VBLANK;
+        gb.cpu.mmu._if = Interrupts::VBLANK;
+        for _ in 0..3 {
+            gb.step().unwrap();
+        }
+        assert_eq!(gb.cpu.a, 1);
+        assert!(!gb.cpu.ime);
+    }
+
+    #[test]
+    fn reti_enables_interrupts_immediately() {
+        let mut gb = gb_with_program(&[0xD9]);
+        gb.cpu.sp = 0xD000;
+        gb.cpu.mmu.write_byte(0x00, 0xD000);
+        gb.cpu.mmu.write_byte(0x02, 0xD001);
+        gb.cpu.mmu.ie = Interrupts::VBLANK;
+        gb.cpu.mmu._if = Interrupts::VBLANK;
+        gb.step().unwrap();
+        assert_eq!(gb.cpu.pc, 0x200);
+        gb.step().unwrap();
+        assert_eq!(gb.cpu.pc, 0x40);
+        assert_eq!(gb.cpu.mmu.read_word(0xD000), 0x200);
+    }
+
+    #[test]
+    fn dmg_stat_write_requests_interrupt() {
+        for &cgb_mode in [false, true].iter() {
+            let mut gb = gb_with_program(&[]);
+            let mmu = &mut gb.cpu.mmu;
+            mmu.cgb_mode = cgb_mode;
+            // the first line after enabling the LCD starts in mode 0
+            mmu.write_byte(0x91, 0xFF40);
+            mmu.write_byte(0x00, 0xFF0F);
+            mmu.write_byte(0x00, 0xFF41);
+            assert_eq!(mmu._if.contains(Interrupts::LCD_STAT), !cgb_mode);
+        }
+    }
+
+    #[test]
+    fn ly_is_read_only() {
+        let mut gb = gb_with_program(&[]);
+        let mmu = &mut gb.cpu.mmu;
+        mmu.write_byte(0x91, 0xFF40);
+        for _ in 0..500 {
+            mmu.tick();
+        }
+        let ly = mmu.read_byte(0xFF44);
+        assert!(ly > 0);
+        mmu.write_byte(0x00, 0xFF44);
+        assert_eq!(mmu.read_byte(0xFF44), ly);
+        assert_eq!(mmu.read_byte(0xFF41) & 0x80, 0x80);
+    }
+
+    #[test]
+    fn cgb_banking_test() {
+        let mut gb = gb_with_program(&[]);
+        let mmu = &mut gb.cpu.mmu;
+        // ignored outside of CGB mode
+        mmu.write_byte(0x01, 0xFF4F);
+        mmu.write_byte(0x03, 0xFF70);
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFF);
+        assert_eq!(mmu.read_byte(0xFF70), 0xFF);
+        mmu.write_byte(0x11, 0x8000);
+        mmu.write_byte(0x22, 0xD000);
+
+        mmu.cgb_mode = true;
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFE);
+        mmu.write_byte(0x01, 0xFF4F);
+        assert_eq!(mmu.read_byte(0xFF4F), 0xFF);
+        assert_eq!(mmu.read_byte(0x8000), 0x00);
+        mmu.write_byte(0x33, 0x8000);
+        assert_eq!(mmu.vram[0x2000], 0x33);
+        assert_eq!(mmu.vram[0], 0x11);
+
+        // bank 0 selects bank 1
+        assert_eq!(mmu.read_byte(0xFF70), 0xF8);
+        assert_eq!(mmu.read_byte(0xD000), 0x22);
+        mmu.write_byte(0x07, 0xFF70);
+        mmu.write_byte(0x44, 0xD000);
+        mmu.write_byte(0x55, 0xC000);
+        mmu.write_byte(0x01, 0xFF70);
+        assert_eq!(mmu.read_byte(0xD000), 0x22);
+        mmu.write_byte(0x07, 0xFF70);
+        assert_eq!(mmu.read_byte(0xF000), 0x44);
+        assert_eq!(mmu.read_byte(0xC000), 0x55);
+    }
+
+    #[test]
+    fn cgb_palette_registers_test() {
+        let mut gb = gb_with_program(&[]);
+        let mmu = &mut gb.cpu.mmu;
+        mmu.write_byte(0x80, 0xFF68);
+        assert_eq!(mmu.read_byte(0xFF68), 0xFF);
+
+        mmu.cgb_mode = true;
+        mmu.write_byte(0x82, 0xFF6A);
+        mmu.write_byte(0x1F, 0xFF6B);
+        mmu.write_byte(0x00, 0xFF6B);
+        assert_eq!(mmu.read_byte(0xFF6A), 0xC4);
+        assert_eq!(mmu.ppu.obj_color_palettes.color(0, 1), 0x001F);
+        assert_eq!(mmu.ppu.bg_color_palettes.color(0, 1), 0x7FFF);
+    }
+
+    // source in work RAM, destination 8100
+    fn gb_with_vram_dma(hdma5: u8) -> Gb {
+        // LD A,hdma5; LDH (55),A
+        let mut gb = gb_with_program(&[0x3E, hdma5, 0xE0, 0x55]);
+        let mmu = &mut gb.cpu.mmu;
+        mmu.cgb_mode = true;
+        for i in 0..0x80u16 {
+            mmu.write_byte(i as u8 + 1, 0xC000 + i);
+        }
+        mmu.write_byte(0xC0, 0xFF51);
+        mmu.write_byte(0x0F, 0xFF52);
+        mmu.write_byte(0xE1, 0xFF53);
+        mmu.write_byte(0x00, 0xFF54);
+        gb
+    }
+
+    fn vram_dma_cycles(gb: &mut Gb) -> u64 {
+        gb.step().unwrap();
+        gb.step().unwrap();
+        let clock = gb.cpu.clock;
+        while gb.cpu.mmu.vram_dma_running() {
+            gb.step().unwrap();
+        }
+        gb.cpu.clock - clock
+    }
+
+    #[test]
+    fn general_purpose_vram_dma() {
+        let mut gb = gb_with_vram_dma(0x01);
+        assert_eq!(vram_dma_cycles(&mut gb), 16);
+        let mmu = &gb.cpu.mmu;
+        assert!(mmu.vram[0x100..0x120].iter().enumerate().all(|(i, &b)| b == i as u8 + 1));
+        assert_eq!(mmu.vram[0x120], 0);
+        assert_eq!(mmu.read_byte(0xFF55), 0xFF);
+        // the CPU went on after the copy
+        assert_eq!(gb.cpu.pc, 0x104);
+
+        let mut gb = gb_with_vram_dma(0x01);
+        gb.cpu.mmu.double_speed = true;
+        assert_eq!(vram_dma_cycles(&mut gb), 32);
+    }
+
+    #[test]
+    fn hblank_vram_dma() {
+        let mut gb = gb_with_vram_dma(0x82);
+        gb.cpu.mmu.write_byte(0x91, 0xFF40);
+        gb.step().unwrap();
+        gb.step().unwrap();
+        let mmu = &mut gb.cpu.mmu;
+        assert_eq!(mmu.read_byte(0xFF55), 0x02);
+        assert_eq!(mmu.vram[0x100], 0);
+
+        while !mmu.vram_dma_running() {
+            mmu.tick();
+        }
+        assert_eq!(mmu.ppu.mode, GpuMode::HBlank);
+        for _ in 0..8 {
+            mmu.tick();
+        }
+        assert!(!mmu.vram_dma_running());
+        assert_eq!(mmu.vram[0x10F], 16);
+        assert_eq!(mmu.vram[0x110], 0);
+        assert_eq!(mmu.read_byte(0xFF55), 0x01);
+
+        // one block per line
+        while mmu.ppu.mode == GpuMode::HBlank {
+            mmu.tick();
+        }
+        while mmu.ppu.mode != GpuMode::HBlank {
+            mmu.tick();
+        }
+        for _ in 0..8 {
+            mmu.tick();
+        }
+        assert_eq!(mmu.vram[0x11F], 32);
+        assert_eq!(mmu.read_byte(0xFF55), 0x00);
+
+        // cancelled before the last block
+        mmu.write_byte(0x00, 0xFF55);
+        assert_eq!(mmu.read_byte(0xFF55), 0x80);
+        for _ in 0..114 * 2 {
+            mmu.tick();
+        }
+        assert_eq!(mmu.vram[0x120], 0);
+    }
+
+    fn start_dma(gb: &mut Gb, src: u8) {
+        gb.cpu.mmu.write_byte(src, 0xFF46);
+    }
+
+    #[test]
+    fn oam_dma_takes_160_cycles() {
+        let mut gb = gb_with_program(&[]);
+        let mmu = &mut gb.cpu.mmu;
+        for i in 0..0xA0u16 {
+            mmu.write_byte(i as u8 + 1, 0xC000 + i);
+        }
+        mmu.write_byte(0x42, 0xFF80);
+        start_dma(&mut gb, 0xC0);
+        let mmu = &mut gb.cpu.mmu;
+
+        // startup delay
+        mmu.tick();
+        assert!(!mmu.dma_running());
+        assert_eq!(mmu.cpu_read_byte(0xFE00), 0);
+
+        mmu.tick();
+        assert!(mmu.dma_running());
+        assert_eq!(mmu.oam[0], 1);
+        assert_eq!(mmu.cpu_read_byte(0xFE00), 0xFF);
+        assert_eq!(mmu.cpu_read_byte(0xC000), 0xFF);
+        assert_eq!(mmu.cpu_read_byte(0xFF80), 0x42);
+        assert_eq!(mmu.cpu_read_byte(0xFF46), 0xC0);
+        mmu.cpu_write_byte(0x00, 0xC000);
+        assert_eq!(mmu.read_byte(0xC000), 1);
+
+        for _ in 0..159 {
+            mmu.tick();
+        }
+        assert!(!mmu.dma_running());
+        assert_eq!(mmu.cpu_read_byte(0xFE9F), 0xA0);
+        assert!(mmu.oam.iter().enumerate().all(|(i, &b)| b == i as u8 + 1));
+    }
+
+    #[test]
+    fn oam_dma_restart() {
+        let mut gb = gb_with_program(&[]);
+        for i in 0..0xA0u16 {
+            gb.cpu.mmu.write_byte(0x11, 0xC000 + i);
+            gb.cpu.mmu.write_byte(0x22, 0xC100 + i);
+        }
+        start_dma(&mut gb, 0xC0);
+        for _ in 0..51 {
+            gb.cpu.mmu.tick();
+        }
+        start_dma(&mut gb, 0xC1);
+        // the old transfer keeps going during the startup of the new one
+        gb.cpu.mmu.tick();
+        assert!(gb.cpu.mmu.dma_running());
+        assert_eq!(gb.cpu.mmu.oam[50], 0x11);
+        for _ in 0..160 {
+            gb.cpu.mmu.tick();
+        }
+        assert!(!gb.cpu.mmu.dma_running());
+        assert!(gb.cpu.mmu.oam.iter().all(|&b| b == 0x22));
+    }
+
+    #[test]
+    fn oam_dma_from_high_sources() {
+        let mut gb = gb_with_program(&[]);
+        gb.cpu.mmu.write_byte(0x33, 0xDF00);
+        gb.cpu.mmu.write_byte(0x44, 0xDF9F);
+        start_dma(&mut gb, 0xFF);
+        for _ in 0..161 {
+            gb.cpu.mmu.tick();
+        }
+        assert_eq!(gb.cpu.mmu.oam[0], 0x33);
+        assert_eq!(gb.cpu.mmu.oam[0x9F], 0x44);
+    }
+
+    #[test]
+    fn memory_accesses_match_cycle_tables() {
+        for opcode in 0..=255u8 {
+            for &flags in [0x00, 0xF0].iter() {
+                let mut gb = gb_with_program(&[opcode, 0x00, 0x00]);
+                gb.cpu.f = flags;
+                let opcode = gb.cpu.fetch_opcode_byte();
+                let cycles = execute(&mut gb.cpu, opcode);
+                if !gb.cpu.locked_up {
+                    assert_eq!(gb.cpu.clock, cycles as u64, "opcode {:02X}", opcode);
+                }
+            }
+            let mut gb = gb_with_program(&[0xCB, opcode]);
+            let prefix = gb.cpu.fetch_opcode_byte();
+            let cycles = execute(&mut gb.cpu, prefix);
+            assert_eq!(gb.cpu.clock, cycles as u64, "opcode CB {:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn accesses_happen_on_their_own_cycle() {
+        // LD A, (FF05) reads TIMA on its 4th cycle, so only an increment
+        // happening within the first 4 cycles is seen
+        for &(cycles_to_increment, tima) in [(4, 1), (5, 0)].iter() {
+            let mut gb = gb_with_program(&[0xFA, 0x05, 0xFF]);
+            gb.cpu.mmu.write_byte(0x04, 0xFF07);
+            gb.cpu.mmu.timer.set_div_internal(0x400 - 4 * cycles_to_increment);
+            gb.step().unwrap();
+            assert_eq!(gb.cpu.a, tima);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use crate::gb::cpu::Cpu;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub enum Model {
+    Dmg0,
+    #[default]
+    Dmg,
+    Mgb,
+    Sgb,
+    Cgb,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name.to_lowercase().as_str() {
+            "dmg0" => Some(Model::Dmg0),
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "cgb" => Some(Model::Cgb),
+            _ => None,
+        }
+    }
+
+    pub fn is_cgb(self) -> bool {
+        self == Model::Cgb
+    }
+}
+
+/// Puts the machine in the state the boot ROM of the given model leaves it in,
+/// right before jumping to the cartridge entry point at 0x0100.
+pub fn init_post_boot_state(cpu: &mut Cpu, model: Model) {
+    init_cpu_registers(cpu, model);
+    init_io_registers(cpu, model);
+    cpu.mmu.timer.set_div_internal(div_after_boot(model));
+    let (line, clock) = ppu_after_boot(model);
+    cpu.mmu.ppu.set_line_position(line, clock);
+    cpu.pc = 0x100;
+    cpu.sp = 0xFFFE;
+}
+
+fn init_cpu_registers(cpu: &mut Cpu, model: Model) {
+    // DMG and MGB boot ROMs leave H and C flags set unless the header checksum is 0
+    let header_checksum = cpu.mmu.read_byte(0x014D);
+    let f_dmg = if header_checksum == 0 { 0x80 } else { 0xB0 };
+
+    //                                        A     F      B     C     D     E     H     L
+    let (a, f, b, c, d, e, h, l) = match model {
+        Model::Dmg0 => (0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+        Model::Dmg => (0x01, f_dmg, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+        Model::Mgb => (0xFF, f_dmg, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+        Model::Sgb => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+        Model::Cgb => (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+    };
+    cpu.a = a;
+    cpu.f = f;
+    cpu.b = b;
+    cpu.c = c;
+    cpu.d = d;
+    cpu.e = e;
+    cpu.h = h;
+    cpu.l = l;
+}
+
+fn div_after_boot(model: Model) -> u16 {
+    match model {
+        Model::Dmg0 => 0x182C,
+        Model::Dmg | Model::Mgb => 0xABCC,
+        // depends on how long the SGB took to receive the header, there is no single value
+        Model::Sgb => 0x0000,
+        Model::Cgb => 0x1EA0,
+    }
+}
+
+/// Line and clock within it the PPU is at when the boot ROM exits. All but the DMG0
+/// leave during the last vblank line, where LY already reads 0 and STAT is 0x85.
+fn ppu_after_boot(model: Model) -> (u8, u32) {
+    match model {
+        // LY=0x91, STAT=0x81
+        Model::Dmg0 => (145, 0),
+        Model::Dmg | Model::Mgb | Model::Sgb => (153, 396),
+        Model::Cgb => (153, 164),
+    }
+}
+
+fn init_io_registers(cpu: &mut Cpu, model: Model) {
+    let mmu = &mut cpu.mmu;
+    let sc = if model.is_cgb() { 0x7F } else { 0x7E };
+    let nr52 = if model == Model::Sgb { 0xF0 } else { 0xF1 };
+
+    mmu.write_byte(0xCF, 0xFF00);   // P1
+    mmu.write_byte(0x00, 0xFF01);   // SB
+    mmu.write_byte(sc, 0xFF02);     // SC
+    mmu.write_byte(0x00, 0xFF05);   // TIMA
+    mmu.write_byte(0x00, 0xFF06);   // TMA
+    mmu.write_byte(0xF8, 0xFF07);   // TAC
+    mmu.write_byte(0xE1, 0xFF0F);   // IF
+    mmu.write_byte(0x80, 0xFF10);   // NR10
+    mmu.write_byte(0xBF, 0xFF11);   // NR11
+    mmu.write_byte(0xF3, 0xFF12);   // NR12
+    mmu.write_byte(0xFF, 0xFF13);   // NR13
+    mmu.write_byte(0xBF, 0xFF14);   // NR14
+    mmu.write_byte(0x3F, 0xFF16);   // NR21
+    mmu.write_byte(0x00, 0xFF17);   // NR22
+    mmu.write_byte(0xFF, 0xFF18);   // NR23
+    mmu.write_byte(0xBF, 0xFF19);   // NR24
+    mmu.write_byte(0x7F, 0xFF1A);   // NR30
+    mmu.write_byte(0xFF, 0xFF1B);   // NR31
+    mmu.write_byte(0x9F, 0xFF1C);   // NR32
+    mmu.write_byte(0xFF, 0xFF1D);   // NR33
+    mmu.write_byte(0xBF, 0xFF1E);   // NR34
+    mmu.write_byte(0xFF, 0xFF20);   // NR41
+    mmu.write_byte(0x00, 0xFF21);   // NR42
+    mmu.write_byte(0x00, 0xFF22);   // NR43
+    mmu.write_byte(0xBF, 0xFF23);   // NR44
+    mmu.write_byte(0x77, 0xFF24);   // NR50
+    mmu.write_byte(0xF3, 0xFF25);   // NR51
+    mmu.write_byte(nr52, 0xFF26);   // NR52
+    mmu.write_byte(0x91, 0xFF40);   // LCDC
+    mmu.write_byte(0x00, 0xFF42);   // SCY
+    mmu.write_byte(0x00, 0xFF43);   // SCX
+    mmu.write_byte(0x00, 0xFF45);   // LYC
+    mmu.write_byte(0xFC, 0xFF47);   // BGP
+    mmu.write_byte(0xFF, 0xFF48);   // OBP0
+    mmu.write_byte(0xFF, 0xFF49);   // OBP1
+    mmu.write_byte(0x00, 0xFF4A);   // WY
+    mmu.write_byte(0x00, 0xFF4B);   // WX
+    mmu.write_byte(0x00, 0xFFFF);   // IE
+    mmu.write_byte(0x01, 0xFF50);   // disable bootrom
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gb::joypad::Joypad;
+    use crate::gb::mbc::NoMbc;
+    use crate::gb::mmu::Mmu;
+    use crate::gb::ppu::Ppu;
+    use crate::gb::timer::Timer;
+
+    fn cpu_with_header_checksum(header_checksum: u8) -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x014D] = header_checksum;
+        let cart = Box::new(NoMbc::new(rom));
+        Cpu::new(Mmu::new(None, cart, Joypad::new(), Timer::new(), Ppu::new()))
+    }
+
+    #[test]
+    fn dmg_post_boot_state_test() {
+        let mut cpu = cpu_with_header_checksum(0x3C);
+        init_post_boot_state(&mut cpu, Model::Dmg);
+        assert_eq!(cpu.af(), 0x01B0);
+        assert_eq!(cpu.bc(), 0x0013);
+        assert_eq!(cpu.de(), 0x00D8);
+        assert_eq!(cpu.hl(), 0x014D);
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(cpu.pc, 0x0100);
+        assert_eq!(cpu.mmu.read_byte(0xFF04), 0xAB);
+        assert_eq!(cpu.mmu.read_byte(0xFF40), 0x91);
+        assert_eq!(cpu.mmu.read_byte(0xFF47), 0xFC);
+        assert_eq!(cpu.mmu.read_byte(0xFF41), 0x85);
+        assert_eq!(cpu.mmu.read_byte(0xFF44), 0x00);
+
+        let mut cpu = cpu_with_header_checksum(0x00);
+        init_post_boot_state(&mut cpu, Model::Dmg);
+        assert_eq!(cpu.af(), 0x0180);
+    }
+
+    #[test]
+    fn model_registers_test() {
+        let mut cpu = cpu_with_header_checksum(0x3C);
+        init_post_boot_state(&mut cpu, Model::Mgb);
+        assert_eq!(cpu.a, 0xFF);
+        init_post_boot_state(&mut cpu, Model::Cgb);
+        assert_eq!(cpu.af(), 0x1180);
+        assert_eq!(cpu.mmu.read_byte(0xFF04), 0x1E);
+        init_post_boot_state(&mut cpu, Model::Sgb);
+        assert_eq!(cpu.hl(), 0xC060);
+    }
+
+    #[test]
+    fn ppu_post_boot_state_test() {
+        let mut cpu = cpu_with_header_checksum(0x3C);
+        init_post_boot_state(&mut cpu, Model::Dmg0);
+        assert_eq!(cpu.mmu.read_byte(0xFF44), 0x91);
+        assert_eq!(cpu.mmu.read_byte(0xFF41), 0x81);
+
+        let mut cpu = cpu_with_header_checksum(0x3C);
+        init_post_boot_state(&mut cpu, Model::Dmg);
+        // the rest of line 153, then the first frame starts at LY 0 in mode 2
+        for _ in 0..(456 - 396) / 4 {
+            cpu.mmu.ppu.step(&[0; 0x2000], &[0; 0xA0]);
+        }
+        assert_eq!(cpu.mmu.read_byte(0xFF44), 0x00);
+        assert_eq!(cpu.mmu.read_byte(0xFF41), 0x86);
+
+        let mut cpu = cpu_with_header_checksum(0x3C);
+        init_post_boot_state(&mut cpu, Model::Cgb);
+        assert_eq!(cpu.mmu.read_byte(0xFF41), 0x85);
+    }
+}
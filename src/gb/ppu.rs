@@ -2,6 +2,11 @@ use crate::gb::vram::DmgColor;
 
 use std::mem;
 use crate::gb::vram::Oam;
+use crate::gb::vram::OamEntry;
+
+use self::fifo::PixelFifo;
+
+mod fifo;
 
 pub struct VBlankInterrupt {}
 
@@ -19,18 +24,10 @@ impl DmgPalette {
     }
 
     pub fn to_u8(&self) -> u8 {
-        fn to_u2(c: DmgColor) -> u8 {
-            match c {
-                DmgColor::Black => 0,
-                DmgColor::DarkGray => 1,
-                DmgColor::LightGray => 2,
-                DmgColor::White => 3,
-            }
-        }
-        to_u2(self.colors[0]) << 6 |
-            to_u2(self.colors[1]) << 4 |
-            to_u2(self.colors[2]) << 2 |
-            to_u2(self.colors[3]) << 0
+        self.colors[3].shade() << 6 |
+            self.colors[2].shade() << 4 |
+            self.colors[1].shade() << 2 |
+            self.colors[0].shade()
     }
 
     pub fn get_color(&self, i: u8) -> DmgColor {
@@ -38,7 +35,92 @@ impl DmgPalette {
     }
 }
 
-#[derive(Eq, PartialEq, Copy, Clone)]
+/// CGB palette RAM: 8 palettes of 4 colors, 15-bit little endian each. It's accessed through
+/// an index register (BCPS/OCPS) and a data register (BCPD/OCPD) that can auto-increment it.
+pub struct ColorPalettes {
+    data: [u8; 64],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl ColorPalettes {
+    pub fn new() -> ColorPalettes {
+        ColorPalettes { data: [0xFF; 64], index: 0, auto_increment: false }
+    }
+
+    pub fn read_index(&self) -> u8 {
+        0x40 | (self.auto_increment as u8) << 7 | self.index
+    }
+
+    pub fn write_index(&mut self, val: u8) {
+        self.index = val & 0x3F;
+        self.auto_increment = val & 0x80 != 0;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, val: u8) {
+        self.data[self.index as usize] = val;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    pub fn set_palette(&mut self, palette: u8, colors: [u16; 4]) {
+        for (i, color) in colors.iter().enumerate() {
+            let idx = (palette as usize * 4 + i) * 2;
+            self.data[idx..idx + 2].copy_from_slice(&color.to_le_bytes());
+        }
+    }
+
+    /// 15-bit color, red in the lowest bits
+    pub fn color(&self, palette: u8, color_idx: u8) -> u16 {
+        let i = (palette as usize * 4 + color_idx as usize) * 2;
+        u16::from_le_bytes([self.data[i], self.data[i + 1]]) & 0x7FFF
+    }
+}
+
+/// How mode 3 is emulated. The scanline renderer draws a whole line at the end of
+/// a fixed-length mode 3, the pixel FIFO draws dot by dot so mid-line register
+/// writes take effect, at a higher cost.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub enum Renderer {
+    #[default]
+    Scanline,
+    Fifo,
+}
+
+impl Renderer {
+    pub fn from_name(name: &str) -> Option<Renderer> {
+        match name {
+            "scanline" => Some(Renderer::Scanline),
+            "fifo" => Some(Renderer::Fifo),
+            _ => None,
+        }
+    }
+}
+
+pub const VRAM_BANK_SIZE: usize = 0x2000;
+
+// CGB BG map attributes, kept in VRAM bank 1 at the address of the tile index
+const BG_ATTR_PALETTE: u8 = 0b111;
+const BG_ATTR_BANK1: u8 = 1 << 3;
+const BG_ATTR_FLIP_X: u8 = 1 << 5;
+const BG_ATTR_FLIP_Y: u8 = 1 << 6;
+const BG_ATTR_PRIORITY: u8 = 1 << 7;
+
+/// The DMG palette a pixel of the framebuffer went through, for frontends coloring them separately
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub enum DmgLayer {
+    #[default]
+    Bg,
+    Obj0,
+    Obj1,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum GpuMode {
     OamAccess,
     VramAccess,
@@ -53,6 +135,12 @@ pub struct Ppu {
     mode_time: u32,
     line: u8,
     pub framebuffer: [DmgColor; 160 * 144],
+    pub framebuffer_layers: [DmgLayer; 160 * 144],
+    // in CGB mode the LCD shows 15-bit colors instead of the four DMG shades
+    pub cgb_mode: bool,
+    // a DMG game on CGB, its shades are colored through the palette RAM
+    pub dmg_compat_mode: bool,
+    pub cgb_framebuffer: [u16; 160 * 144],
 
     lyc_interrupt_enable: bool,
     oam_interrupt_enable: bool,
@@ -67,15 +155,44 @@ pub struct Ppu {
 
     pub w_x: u8,
     pub w_y: u8,
+    // the window keeps its own line counter, advanced only on lines it was drawn on
+    window_line: u8,
+    // set once LY matched WY during the frame
+    window_y_triggered: bool,
+    // WX=166 makes the window cover the whole next line
+    window_fills_next_line: bool,
 
     pub bg_palette: DmgPalette,
     pub obj0_palette: DmgPalette,
     pub obj1_palette: DmgPalette,
+    pub bg_color_palettes: ColorPalettes,
+    pub obj_color_palettes: ColorPalettes,
 
     prev_mode: GpuMode,
-    prev_ly: u8,
+    // the enabled STAT sources are ORed into one line, an interrupt is requested on its rising edge
+    stat_line: bool,
+    // LY already reads 0 for most of line 153
+    last_line: bool,
+
+    // the first line after the LCD gets enabled starts in mode 0 instead of 2
+    lcd_just_enabled: bool,
+    // a frame was finished, or a blank one passed while the LCD is off
+    frame_done: bool,
+
+    pub renderer: Renderer,
+    // renderer used for the current line, a change takes effect on the next one
+    line_renderer: Renderer,
+    fifo: PixelFifo,
+    // mode 0 lasts for what's left of the line after mode 3
+    hblank_length: u32,
 }
 
+const CLOCKS_PER_FRAME: u32 = 70224;
+const CGB_WHITE: u16 = 0x7FFF;
+const OAM_SEARCH_CLOCKS: u32 = 80;
+const SCANLINE_MODE3_CLOCKS: u32 = 172;
+const SCANLINE_HBLANK_CLOCKS: u32 = 204;
+
 impl Ppu {
     pub fn new() -> Ppu {
         Ppu {
@@ -85,6 +202,10 @@ impl Ppu {
             mode_time: 0,
             line: 0,
             framebuffer: [DmgColor::White; 160 * 144],
+            framebuffer_layers: [DmgLayer::Bg; 160 * 144],
+            cgb_mode: false,
+            dmg_compat_mode: false,
+            cgb_framebuffer: [CGB_WHITE; 160 * 144],
 
             ly: 0,
             lyc: 0,
@@ -93,6 +214,9 @@ impl Ppu {
 
             w_x: 0,
             w_y: 0,
+            window_line: 0,
+            window_y_triggered: false,
+            window_fills_next_line: false,
 
             lyc_interrupt_enable: false,
             oam_interrupt_enable: false,
@@ -103,12 +227,73 @@ impl Ppu {
             bg_palette: DmgPalette::default(),
             obj0_palette: DmgPalette::default(),
             obj1_palette: DmgPalette::default(),
+            bg_color_palettes: ColorPalettes::new(),
+            obj_color_palettes: ColorPalettes::new(),
 
             prev_mode: GpuMode::OamAccess,
-            prev_ly: 0,
+            stat_line: false,
+            last_line: false,
+
+            lcd_just_enabled: false,
+            frame_done: false,
+
+            renderer: Renderer::default(),
+            line_renderer: Renderer::default(),
+            fifo: PixelFifo::default(),
+            hblank_length: SCANLINE_HBLANK_CLOCKS,
         }
     }
 
+    fn start_frame(&mut self) {
+        self.window_line = 0;
+        self.window_y_triggered = false;
+        self.window_fills_next_line = false;
+    }
+
+    /// The LCD shows a blank white screen while it's not being driven
+    pub fn blank_screen(&mut self) {
+        self.framebuffer = [DmgColor::White; 160 * 144];
+        self.framebuffer_layers = [DmgLayer::Bg; 160 * 144];
+        self.cgb_framebuffer = [CGB_WHITE; 160 * 144];
+    }
+
+    pub fn write_lcdc(&mut self, val: u8) {
+        let was_enabled = self.lcdc.lcd_display_enable;
+        self.lcdc = Lcdc::from_byte(val);
+        let enabled = self.lcdc.lcd_display_enable;
+        if was_enabled != enabled {
+            // LY stays at 0 and the mode at 0 while the LCD is off, leaving VRAM and OAM unlocked
+            self.ly = 0;
+            self.mode = GpuMode::HBlank;
+            self.mode_time = 0;
+            self.stat_line = false;
+            self.last_line = false;
+            self.lcd_just_enabled = enabled;
+            self.start_frame();
+            if !enabled {
+                self.blank_screen();
+            }
+        }
+    }
+
+    /// Moves the enabled LCD to `clock` cycles into `line`, where a boot ROM hands over
+    pub fn set_line_position(&mut self, line: u8, clock: u32) {
+        self.mode = if line >= 144 { GpuMode::VBlank } else { GpuMode::OamAccess };
+        self.prev_mode = self.mode;
+        self.mode_time = clock;
+        self.lcd_just_enabled = false;
+        // LY already reads 0 for most of line 153
+        self.last_line = line == 153 && clock > 4;
+        self.ly = if self.last_line { 0 } else { line };
+        self.lyc_coincidence = self.ly == self.lyc;
+        self.stat_line = false;
+    }
+
+    /// True once per frame, also while the LCD is off so the frontend keeps presenting
+    pub fn take_frame_done(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_done, false)
+    }
+
     pub fn read_lcdstat(&self) -> u8 {
         let mode_code = match self.mode {
             GpuMode::OamAccess => 2,
@@ -117,7 +302,7 @@ impl Ppu {
             GpuMode::VBlank => 1,
         };
 
-        mode_code |
+        0x80 | mode_code |
             (self.lyc_interrupt_enable as u8) << 6 |
             (self.oam_interrupt_enable as u8) << 5 |
             (self.vblank_interrupt_enable as u8) << 4 |
@@ -132,29 +317,74 @@ impl Ppu {
         self.hblank_interrupt_enable = val & (1 << 3) != 0;
     }
 
+    /// On DMG a write to STAT acts as if all sources were enabled for a cycle,
+    /// which requests an interrupt in mode 0, mode 1 or on LY=LYC
+    pub fn stat_write_glitch(&mut self) -> bool {
+        if !self.lcdc.lcd_display_enable || self.stat_line {
+            return false;
+        }
+        let glitch = self.lyc_coincidence || self.mode == GpuMode::HBlank || self.mode == GpuMode::VBlank;
+        self.stat_line = glitch;
+        glitch
+    }
+
+    fn stat_line_high(&self, vblank_started: bool) -> bool {
+        (self.lyc_interrupt_enable && self.lyc_coincidence) ||
+            (self.hblank_interrupt_enable && self.mode == GpuMode::HBlank) ||
+            (self.vblank_interrupt_enable && self.mode == GpuMode::VBlank) ||
+            (self.oam_interrupt_enable && self.mode == GpuMode::OamAccess) ||
+            // the mode 2 source also triggers at the start of vblank
+            (self.oam_interrupt_enable && vblank_started)
+    }
+
     pub fn step(&mut self, vram: &[u8], oam: &[u8]) -> (Option<VBlankInterrupt>, Option<StatInterrupt>) {
         self.mode_time += 4;    // mode_time is in clock cycles
+        if !self.lcdc.lcd_display_enable {
+            if self.mode_time >= CLOCKS_PER_FRAME {
+                self.mode_time = 0;
+                self.frame_done = true;
+            }
+            return (None, None);
+        }
         self.prev_mode = self.mode;
-        self.prev_ly = self.ly;
         match self.mode {
             GpuMode::OamAccess => {
-                if self.mode_time > 80 {
+                if self.mode_time >= OAM_SEARCH_CLOCKS {
                     self.mode_time = 0;
-                    self.mode = GpuMode::VramAccess;
+                    self.enter_mode3(oam);
+                }
+            }
+            GpuMode::VramAccess if self.line_renderer == Renderer::Fifo => {
+                // one dot per clock
+                for dot in 0..4 {
+                    if self.fifo_dot(vram) {
+                        self.mode_time = 3 - dot;
+                        self.mode = GpuMode::HBlank;
+                        self.hblank_length = (456 - OAM_SEARCH_CLOCKS).saturating_sub(self.fifo.dots());
+                        break;
+                    }
                 }
             }
             GpuMode::VramAccess => {
-                if self.mode_time > 172 {
+                if self.mode_time >= SCANLINE_MODE3_CLOCKS {
                     if self.ly <= 144 {
                         self.render_line(self.ly, vram, oam);
                     }
 
                     self.mode_time = 0;
                     self.mode = GpuMode::HBlank;
+                    self.hblank_length = SCANLINE_HBLANK_CLOCKS;
+                }
+            }
+            GpuMode::HBlank if self.lcd_just_enabled => {
+                if self.mode_time >= 76 {
+                    self.mode_time = 0;
+                    self.lcd_just_enabled = false;
+                    self.enter_mode3(oam);
                 }
             }
             GpuMode::HBlank => {
-                if self.mode_time > 204 {
+                if self.mode_time >= self.hblank_length {
                     self.mode_time = 0;
                     self.ly += 1;
 
@@ -167,13 +397,18 @@ impl Ppu {
                 }
             }
             GpuMode::VBlank => {
-                if self.mode_time > 456 {
+                if self.ly == 153 && self.mode_time > 4 {
+                    self.ly = 0;
+                    self.last_line = true;
+                }
+                if self.mode_time >= 456 {
                     self.mode_time = 0;
-                    self.ly += 1;
-
-                    if self.ly > 153 {
+                    if self.last_line {
+                        self.last_line = false;
                         self.mode = GpuMode::OamAccess;
-                        self.ly = 0;
+                        self.start_frame();
+                    } else {
+                        self.ly += 1;
                     }
                 }
             }
@@ -182,11 +417,10 @@ impl Ppu {
         self.lyc_coincidence = self.ly == self.lyc;
         let mode_changed = self.mode != self.prev_mode;
         let vblank_interrupt = self.mode == GpuMode::VBlank && mode_changed;
-        let mut stat_interrupt = false;
-        stat_interrupt |= self.lyc_interrupt_enable && self.ly != self.prev_ly && self.ly == self.lyc;
-        stat_interrupt |= self.hblank_interrupt_enable && self.mode == GpuMode::HBlank && mode_changed;
-        stat_interrupt |= self.vblank_interrupt_enable && vblank_interrupt; // TODO not sure if flag is for STAT interrupt
-        stat_interrupt |= self.oam_interrupt_enable && self.mode == GpuMode::OamAccess && mode_changed;
+        self.frame_done |= vblank_interrupt;
+        let stat_line = self.stat_line_high(vblank_interrupt);
+        let stat_interrupt = stat_line && !self.stat_line;
+        self.stat_line = stat_line;
 
         let vblank_interrupt = if vblank_interrupt { Some(VBlankInterrupt {}) } else { None };
         let stat_interrupt = if stat_interrupt { Some(StatInterrupt {}) } else { None };
@@ -194,36 +428,61 @@ impl Ppu {
         (vblank_interrupt, stat_interrupt)
     }
 
+    fn enter_mode3(&mut self, oam: &[u8]) {
+        self.mode = GpuMode::VramAccess;
+        self.line_renderer = self.renderer;
+        if self.line_renderer == Renderer::Fifo {
+            self.fifo_start_line(oam);
+        }
+    }
+
     fn render_line(&mut self, ly: u8, vram: &[u8], _oam: &[u8]) {
-        assert!(!self.lcdc.tall_sprites);
-        #[derive(PartialEq, Eq)]
+        #[derive(Copy, Clone)]
         enum Pixel {
-            Bg(u8),
-            Sprite0(u8),
-            Sprite1(u8),
+            // color index and CGB attributes of the tile
+            Bg(u8, u8),
+            // color index and palette
+            Sprite(u8, u8),
         }
         const VRAM_OFFSET: u16 = 0x8000;
-        let window_enabled = self.lcdc.window_enabled && ly > self.w_y;
+        if ly == self.w_y {
+            self.window_y_triggered = true;
+        }
+        let window_visible = self.lcdc.window_enabled && self.window_y_triggered && self.w_x <= 166;
+        // screen X of the first window pixel, WX=0 shifts the window further left by SCX % 8
+        let window_start: i16 = if self.window_fills_next_line {
+            0
+        } else if self.w_x == 0 {
+            -7 - (self.sc_x % 8) as i16
+        } else {
+            self.w_x as i16 - 7
+        };
         let mut line: Vec<Pixel> = Vec::with_capacity(160);
 
         let bg_tile_row = ly.wrapping_add(self.sc_y) / 8;
         let bg_row_in_tile = ly.wrapping_add(self.sc_y) % 8;
 
-        let w_tile_row = ly.wrapping_sub(self.w_y) / 8;
-        let w_row_in_tile = ly.wrapping_sub(self.w_y) % 8;
-        for x in 0..160 {
-            let window_over_bg = window_enabled && x + 7 > self.w_x;
+        let w_tile_row = self.window_line / 8;
+        let w_row_in_tile = self.window_line % 8;
+        for x in 0..160u8 {
+            // on DMG, LCDC bit 0 blanks both the BG and the window
+            if !self.cgb_mode && !self.lcdc.bg_window_priority {
+                line.push(Pixel::Bg(0, 0));
+                continue;
+            }
+            let window_over_bg = window_visible && x as i16 >= window_start;
             let tilemap1: bool;
             let tilemap_x: u8;
             let tilemap_y: u8;
             let x_in_tile: u8;
             let y_in_tile: u8;
             if window_over_bg {
+                let window_x = (x as i16 - window_start) as u8;
                 tilemap1 = self.lcdc.window_tilemap_select1;
-                tilemap_x = (x + 7).wrapping_sub(self.w_x) / 8;
+                tilemap_x = window_x / 8;
                 tilemap_y = w_tile_row;
                 y_in_tile = w_row_in_tile;
-                x_in_tile = (x + 7).wrapping_sub(self.w_x) % 8;
+                x_in_tile = window_x % 8;
             } else {
                 tilemap1 = self.lcdc.bg_tilemap_select1;
                 tilemap_x = x.wrapping_add(self.sc_x) / 8;
@@ -240,71 +499,58 @@ impl Ppu {
             let tilemap_idx = tilemap_y as u16 * 32 + tilemap_x as u16;
             let tile_idx_addr = (tilemap_start_addr - VRAM_OFFSET + tilemap_idx) as usize;
             let tile_idx = vram[tile_idx_addr];
+            let attrs = if self.cgb_mode { vram[VRAM_BANK_SIZE + tile_idx_addr] } else { 0 };
+            let x_in_tile = if attrs & BG_ATTR_FLIP_X != 0 { 7 - x_in_tile } else { x_in_tile };
+            let y_in_tile = if attrs & BG_ATTR_FLIP_Y != 0 { 7 - y_in_tile } else { y_in_tile };
+            let bank_offset = if attrs & BG_ATTR_BANK1 != 0 { VRAM_BANK_SIZE } else { 0 };
             let tileset_mode1 = self.lcdc.bg_window_tile_data_select1;
 
             let tile_addr = get_tile_addr(tile_idx, tileset_mode1, vram);
-            let tile_lo = vram[(tile_addr - VRAM_OFFSET + (y_in_tile * 2) as u16 + 0) as usize];
-            let tile_hi = vram[(tile_addr - VRAM_OFFSET + (y_in_tile * 2) as u16 + 1) as usize];
+            let row_addr = bank_offset + (tile_addr - VRAM_OFFSET + (y_in_tile * 2) as u16) as usize;
+            let tile_lo = vram[row_addr];
+            let tile_hi = vram[row_addr + 1];
 
 
             let color_hi_bit = 1 & (tile_hi >> (7 - x_in_tile));
             let color_lo_bit = 1 & (tile_lo >> (7 - x_in_tile));
 
             let color_idx = (color_hi_bit << 1) | color_lo_bit;
-            line.push(Pixel::Bg(color_idx));
+            line.push(Pixel::Bg(color_idx, attrs));
+        }
+        if window_visible {
+            self.window_line += 1;
         }
+        self.window_fills_next_line = window_visible && self.w_x == 166;
 
         // SPRITES
 
         if self.lcdc.sprites_enabled {
-            let tile_height: u8;
-            if self.lcdc.tall_sprites {
-                tile_height = 16;
-            } else {
-                tile_height = 8
-            }
+            let tile_height: i16 = if self.lcdc.tall_sprites { 16 } else { 8 };
+            let sprites_on_line = self.sprites_on_line(ly, tile_height, _oam);
+            for x in 0..160i16 {
+                // sprites are sorted by priority, the first non-transparent pixel wins
+                // even if the BG ends up drawn over it
+                let sprite_pixel = sprites_on_line.iter().find_map(|spr| {
+                    let x_in_tile = x - (spr.pos_x as i16 - 8);
+                    if !(0..8).contains(&x_in_tile) {
+                        return None;
+                    }
+                    let x_in_tile = if spr.flip_x { 7 - x_in_tile } else { x_in_tile };
+                    let (tile_lo, tile_hi) = sprite_tile_row(spr, ly, tile_height, self.cgb_mode, vram);
 
-            let oam = Oam::from_bytes(_oam);
-            let mut sprites_on_line = vec![];
-            for spr in oam.sprites.iter() {
-                if sprites_on_line.len() < 10 {
-                let spr_visible_on_line =
-                        ly + 16 >= spr.pos_y &&
-                        ly + 16 < spr.pos_y + tile_height;
-                if spr_visible_on_line { sprites_on_line.push(spr) }
-                }
-            }
-            for x in 0..160 {
-                for spr in &sprites_on_line {
-                    let should_draw = x + 8 >= spr.pos_x && x < spr.pos_x;
-                    if should_draw {
-                        let tile_idx = spr.tile_idx;
-                        let y_in_tile = ly + 16 - (spr.pos_y );
-                        let y_in_tile = if spr.flip_y { tile_height - 1 - y_in_tile } else { y_in_tile };
-                        let x_in_tile = x + 8 - (spr.pos_x);
-                        let x_in_tile = if spr.flip_x { 7 - x_in_tile } else { x_in_tile };
-                        assert!(y_in_tile < tile_height);
-                        assert!(x_in_tile < 8);
-
-                        let tile_addr = get_tile_addr(tile_idx, true, vram);
-
-                        let tile_lo = vram[(tile_addr - VRAM_OFFSET + (y_in_tile * 2) as u16 + 0) as usize];
-                        let tile_hi = vram[(tile_addr - VRAM_OFFSET + (y_in_tile * 2) as u16 + 1) as usize];
-
-                        let color_hi_bit = 1 & (tile_hi >> (7 - x_in_tile));
-                        let color_lo_bit = 1 & (tile_lo >> (7 - x_in_tile));
-
-                        let color_idx = (color_hi_bit << 1) | color_lo_bit;
-                        let spr_high_prio = self.lcdc.bg_window_priority;
-
-                        let transparent = color_idx == 0;
-                        if !transparent && (spr_high_prio || line[x as usize] == Pixel::Bg(0)) {
-                            if spr.palette1 {
-                                line[x as usize] = Pixel::Sprite1(color_idx);
-                            } else {
-                                line[x as usize] = Pixel::Sprite0(color_idx);
-                            }
-                        }
+                    let color_hi_bit = 1 & (tile_hi >> (7 - x_in_tile));
+                    let color_lo_bit = 1 & (tile_lo >> (7 - x_in_tile));
+                    let color_idx = (color_hi_bit << 1) | color_lo_bit;
+                    if color_idx == 0 { None } else { Some((spr, color_idx)) }
+                });
+                if let Some((spr, color_idx)) = sprite_pixel {
+                    let x = x as usize;
+                    let hidden = match line[x] {
+                        Pixel::Bg(bg, attrs) => self.bg_over_sprite(bg, attrs, spr.low_priority),
+                        Pixel::Sprite(..) => false,
+                    };
+                    if !hidden {
+                        line[x] = Pixel::Sprite(color_idx, self.sprite_palette(spr));
                     }
                 }
             }
@@ -312,17 +558,105 @@ impl Ppu {
 
 
         for x in 0..160 {
-            let color = match line[x] {
-                Pixel::Bg(idx) => self.bg_palette.get_color(idx),
-                Pixel::Sprite0(idx) => self.obj0_palette.get_color(idx),
-                Pixel::Sprite1(idx) => self.obj1_palette.get_color(idx),
-            };
-            let framebuffer_idx = ly as usize * 160 + x;
-            self.framebuffer[framebuffer_idx] = color;
+            match line[x] {
+                Pixel::Bg(idx, attrs) => self.put_bg_pixel(ly, x as u8, idx, attrs),
+                Pixel::Sprite(idx, palette) => self.put_sprite_pixel(ly, x as u8, idx, palette),
+            }
+        }
+    }
+
+    /// On DMG only the sprite flag matters. On CGB the tile attributes can also put the BG
+    /// in front, unless LCDC bit 0 is clear, which puts every sprite over the BG.
+    fn bg_over_sprite(&self, bg: u8, bg_attrs: u8, sprite_low_priority: bool) -> bool {
+        if bg == 0 || (self.cgb_mode && !self.lcdc.bg_window_priority) {
+            return false;
+        }
+        sprite_low_priority || (self.cgb_mode && bg_attrs & BG_ATTR_PRIORITY != 0)
+    }
+
+    // OBP0 or OBP1 on DMG, one of the 8 color palettes on CGB
+    fn sprite_palette(&self, spr: &OamEntry) -> u8 {
+        if self.cgb_mode { spr.cgb_palette } else { spr.palette1 as u8 }
+    }
+
+    /// True if the LCD shows the 15-bit colors of `cgb_framebuffer`
+    pub fn color_output(&self) -> bool {
+        self.cgb_mode || self.dmg_compat_mode
+    }
+
+    fn put_bg_pixel(&mut self, ly: u8, x: u8, color_idx: u8, attrs: u8) {
+        let idx = ly as usize * 160 + x as usize;
+        if self.cgb_mode {
+            self.cgb_framebuffer[idx] = self.bg_color_palettes.color(attrs & BG_ATTR_PALETTE, color_idx);
+            return;
+        }
+        let color = self.bg_palette.get_color(color_idx);
+        self.framebuffer[idx] = color;
+        self.framebuffer_layers[idx] = DmgLayer::Bg;
+        if self.dmg_compat_mode {
+            self.cgb_framebuffer[idx] = self.bg_color_palettes.color(0, color.shade());
+        }
+    }
+
+    fn put_sprite_pixel(&mut self, ly: u8, x: u8, color_idx: u8, palette: u8) {
+        let idx = ly as usize * 160 + x as usize;
+        if self.cgb_mode {
+            self.cgb_framebuffer[idx] = self.obj_color_palettes.color(palette, color_idx);
+            return;
+        }
+        let (color, layer) = if palette == 1 {
+            (self.obj1_palette.get_color(color_idx), DmgLayer::Obj1)
+        } else {
+            (self.obj0_palette.get_color(color_idx), DmgLayer::Obj0)
+        };
+        self.framebuffer[idx] = color;
+        self.framebuffer_layers[idx] = layer;
+        if self.dmg_compat_mode {
+            self.cgb_framebuffer[idx] = self.obj_color_palettes.color(palette, color.shade());
         }
     }
 }
 
+impl Ppu {
+    /// Picks the first 10 sprites in OAM that overlap the line vertically (their X
+    /// doesn't matter, off-screen sprites count too), ordered by priority: lower X
+    /// first, then lower OAM index on DMG, only the OAM index on CGB.
+    fn sprites_on_line(&self, ly: u8, tile_height: i16, oam: &[u8]) -> Vec<OamEntry> {
+        const MAX_SPRITES_PER_LINE: usize = 10;
+        let oam = Oam::from_bytes(oam);
+        let mut sprites: Vec<OamEntry> = oam.sprites.iter()
+            .filter(|spr| {
+                let top = spr.pos_y as i16 - 16;
+                (top..top + tile_height).contains(&(ly as i16))
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .cloned()
+            .collect();
+        if !self.cgb_mode {
+            // stable sort keeps OAM order for sprites with the same X
+            sprites.sort_by_key(|spr| spr.pos_x);
+        }
+        sprites
+    }
+}
+
+/// Tile data of the sprite row shown on the given line, flipped vertically if needed
+fn sprite_tile_row(spr: &OamEntry, ly: u8, tile_height: i16, cgb_mode: bool, vram: &[u8]) -> (u8, u8) {
+    const VRAM_OFFSET: u16 = 0x8000;
+    let y_in_tile = ly as i16 - (spr.pos_y as i16 - 16);
+    let y_in_tile = if spr.flip_y { tile_height - 1 - y_in_tile } else { y_in_tile };
+    // in 8x16 mode the tile index LSB is ignored, rows 8-15 come from the next tile
+    let tile_idx = if tile_height == 16 { spr.tile_idx & 0xFE } else { spr.tile_idx };
+
+    let bank_offset = if cgb_mode && spr.vram_bank1 { VRAM_BANK_SIZE } else { 0 };
+
+    let tile_addr = get_tile_addr(tile_idx, true, vram) + (y_in_tile * 2) as u16;
+    let row_addr = bank_offset + (tile_addr - VRAM_OFFSET) as usize;
+    let tile_lo = vram[row_addr];
+    let tile_hi = vram[row_addr + 1];
+    (tile_lo, tile_hi)
+}
+
 fn get_tile_addr(tile_idx: u8, tileset_mode1: bool, _vram: &[u8]) -> u16 {
     if tileset_mode1 {
         0x8000 + (tile_idx as u16 * 16) as u16
@@ -412,3 +746,449 @@ fn color_palette(palette_register: u8) -> [DmgColor; 4] {
     let c3 = color_from_index((palette_register >> 6) & MASK);
     [c0, c1, c2, c3]
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SPRITES_ON: u8 = 0b1001_0011;
+    const TALL_SPRITES_ON: u8 = 0b1001_0111;
+
+    // tile n (1-3) has all its pixels set to color n
+    fn vram_with_solid_tiles() -> Vec<u8> {
+        let mut vram = vec![0; 0x2000];
+        for tile in 1..4 {
+            for row in 0..8 {
+                vram[tile * 16 + row * 2] = if tile & 1 != 0 { 0xFF } else { 0 };
+                vram[tile * 16 + row * 2 + 1] = if tile & 2 != 0 { 0xFF } else { 0 };
+            }
+        }
+        vram
+    }
+
+    fn set_sprite(oam: &mut [u8], idx: usize, x: u8, y: u8, tile: u8, flags: u8) {
+        oam[idx * 4..idx * 4 + 4].copy_from_slice(&[y, x, tile, flags]);
+    }
+
+    fn render(lcdc: u8, vram: &[u8], oam: &[u8], ly: u8) -> Vec<DmgColor> {
+        let mut ppu = Ppu::new();
+        ppu.lcdc = Lcdc::from_byte(lcdc);
+        ppu.bg_palette = DmgPalette::from_u8(0xE4);
+        ppu.obj0_palette = DmgPalette::from_u8(0xE4);
+        ppu.render_line(ly, vram, oam);
+        ppu.framebuffer[ly as usize * 160..(ly as usize + 1) * 160].to_vec()
+    }
+
+    const WINDOW_ON: u8 = 0b1111_0001;
+
+    fn line(ppu: &Ppu, ly: u8) -> Vec<DmgColor> {
+        ppu.framebuffer[ly as usize * 160..(ly as usize + 1) * 160].to_vec()
+    }
+
+    // window tilemap rows alternate between tile 1 and tile 3
+    fn window_ppu() -> (Ppu, Vec<u8>) {
+        let mut vram = vram_with_solid_tiles();
+        for row in 0..32 {
+            let tile = if row % 2 == 0 { 1 } else { 3 };
+            for col in 0..32 {
+                vram[0x1C00 + row * 32 + col] = tile;
+            }
+        }
+        let mut ppu = Ppu::new();
+        ppu.lcdc = Lcdc::from_byte(WINDOW_ON);
+        ppu.bg_palette = DmgPalette::from_u8(0xE4);
+        ppu.w_x = 7;
+        (ppu, vram)
+    }
+
+    #[test]
+    fn window_line_counter_test() {
+        let (mut ppu, vram) = window_ppu();
+        let oam = vec![0; 160];
+        ppu.w_y = 2;
+        for ly in 0..6 {
+            ppu.render_line(ly, &vram, &oam);
+        }
+        assert_eq!(line(&ppu, 1)[0], DmgColor::White);
+        assert_eq!(line(&ppu, 2)[0], DmgColor::LightGray);
+
+        // lines without the window don't advance its counter
+        ppu.lcdc.window_enabled = false;
+        for ly in 6..12 {
+            ppu.render_line(ly, &vram, &oam);
+        }
+        ppu.lcdc.window_enabled = true;
+        for ly in 12..20 {
+            ppu.render_line(ly, &vram, &oam);
+        }
+        assert_eq!(line(&ppu, 11)[0], DmgColor::White);
+        // window line 4, not LY - WY
+        assert_eq!(line(&ppu, 12)[0], DmgColor::LightGray);
+        assert_eq!(line(&ppu, 15)[0], DmgColor::LightGray);
+        assert_eq!(line(&ppu, 16)[0], DmgColor::Black);
+
+        ppu.start_frame();
+        ppu.render_line(0, &vram, &oam);
+        assert_eq!(line(&ppu, 0)[0], DmgColor::White);
+    }
+
+    #[test]
+    fn window_x_quirks_test() {
+        let (mut ppu, mut vram) = window_ppu();
+        let oam = vec![0; 160];
+        // tile 1: left half color 1, right half color 3
+        for row in 0..8 {
+            vram[16 + row * 2] = 0xFF;
+            vram[16 + row * 2 + 1] = 0x0F;
+        }
+        ppu.w_x = 3;
+        ppu.render_line(0, &vram, &oam);
+        assert_eq!(line(&ppu, 0)[0], DmgColor::Black);
+        assert_eq!(line(&ppu, 0)[4], DmgColor::LightGray);
+
+        // WX=166 shows a single window pixel, then covers the whole next line
+        let (mut ppu, vram) = window_ppu();
+        ppu.w_x = 166;
+        ppu.render_line(0, &vram, &oam);
+        assert_eq!(line(&ppu, 0)[158], DmgColor::White);
+        assert_eq!(line(&ppu, 0)[159], DmgColor::LightGray);
+        ppu.render_line(1, &vram, &oam);
+        assert_eq!(line(&ppu, 1)[0], DmgColor::LightGray);
+
+        ppu.w_x = 167;
+        ppu.render_line(2, &vram, &oam);
+        assert!(line(&ppu, 2).iter().all(|&c| c == DmgColor::White));
+    }
+
+    #[test]
+    fn lcdc_bit0_blanks_bg_and_window() {
+        let (mut ppu, vram) = window_ppu();
+        let mut oam = vec![0; 160];
+        set_sprite(&mut oam, 0, 8, 16, 2, 0x80);
+        ppu.lcdc = Lcdc::from_byte(WINDOW_ON & !1 | 0b10);
+        ppu.obj0_palette = DmgPalette::from_u8(0xE4);
+        ppu.render_line(0, &vram, &oam);
+        assert_eq!(line(&ppu, 0)[0], DmgColor::DarkGray);
+        assert_eq!(line(&ppu, 0)[8], DmgColor::White);
+    }
+
+    fn step_lines(ppu: &mut Ppu, lines: u32) -> (u32, u32) {
+        let vram = vec![0; 0x2000];
+        let oam = vec![0; 160];
+        let (mut vblanks, mut stats) = (0, 0);
+        for _ in 0..lines * 114 {
+            let (vblank, stat) = ppu.step(&vram, &oam);
+            vblanks += vblank.is_some() as u32;
+            stats += stat.is_some() as u32;
+        }
+        (vblanks, stats)
+    }
+
+    #[test]
+    fn lcd_off_test() {
+        let mut ppu = Ppu::new();
+        ppu.write_lcdc(0x91);
+        ppu.write_lcdstat(0x78);
+        step_lines(&mut ppu, 10);
+        assert!(ppu.ly > 0);
+        ppu.framebuffer[0] = DmgColor::Black;
+
+        ppu.write_lcdc(0x11);
+        assert_eq!(ppu.ly, 0);
+        assert_eq!(ppu.read_lcdstat() & 0b11, 0);
+        assert_eq!(ppu.mode, GpuMode::HBlank);
+        assert_eq!(ppu.framebuffer[0], DmgColor::White);
+        ppu.take_frame_done();
+
+        assert_eq!(step_lines(&mut ppu, 154), (0, 0));
+        assert_eq!(ppu.ly, 0);
+        assert!(ppu.take_frame_done());
+    }
+
+    #[test]
+    fn lcd_on_starts_shortened_frame() {
+        let mut ppu = Ppu::new();
+        let vram = vec![0; 0x2000];
+        let oam = vec![0; 160];
+        ppu.write_lcdc(0x91);
+        // no OAM search on the first line
+        assert_eq!(ppu.read_lcdstat() & 0b11, 0);
+        let mut steps = 0;
+        while ppu.mode == GpuMode::HBlank {
+            ppu.step(&vram, &oam);
+            steps += 1;
+        }
+        assert_eq!(ppu.mode, GpuMode::VramAccess);
+        assert_eq!(steps, 19);
+
+        let mut steps_to_vblank = steps + 1;
+        while ppu.step(&vram, &oam).0.is_none() {
+            steps_to_vblank += 1;
+        }
+        // line 0 is 4 clocks shorter than the usual 456
+        assert_eq!(steps_to_vblank, 144 * 114 - 1);
+        assert!(ppu.take_frame_done());
+    }
+
+    fn stat_interrupts_from_mode3(stat: u8, lines: u32) -> u32 {
+        let mut ppu = Ppu::new();
+        let vram = vec![0; 0x2000];
+        let oam = vec![0; 160];
+        ppu.write_lcdc(0x91);
+        step_lines(&mut ppu, 2);
+        while ppu.mode != GpuMode::VramAccess {
+            ppu.step(&vram, &oam);
+        }
+        ppu.write_lcdstat(stat);
+        step_lines(&mut ppu, lines).1
+    }
+
+    #[test]
+    fn stat_blocking_test() {
+        assert_eq!(stat_interrupts_from_mode3(0x08, 10), 10);
+        assert_eq!(stat_interrupts_from_mode3(0x20, 10), 10);
+        // mode 0 runs straight into mode 2, the line never goes low in between
+        assert_eq!(stat_interrupts_from_mode3(0x28, 10), 10);
+        // mode 1 source, not the vblank interrupt
+        assert_eq!(stat_interrupts_from_mode3(0x10, 154), 1);
+        // the line stays high through vblank, mode 2 of line 0 doesn't trigger again
+        assert_eq!(stat_interrupts_from_mode3(0x30, 154), 144);
+    }
+
+    #[test]
+    fn lyc_0_matches_on_line_153() {
+        let mut ppu = Ppu::new();
+        let vram = vec![0; 0x2000];
+        let oam = vec![0; 160];
+        ppu.write_lcdc(0x91);
+        ppu.lyc = 0;
+        ppu.write_lcdstat(0x40);
+        while ppu.ly != 153 {
+            ppu.step(&vram, &oam);
+        }
+        assert!(ppu.step(&vram, &oam).1.is_none());
+        assert_eq!(ppu.ly, 153);
+        assert!(ppu.step(&vram, &oam).1.is_some());
+        assert_eq!(ppu.ly, 0);
+        assert_eq!(ppu.mode, GpuMode::VBlank);
+        assert_ne!(ppu.read_lcdstat() & 0b100, 0);
+
+        // still matching on line 0, so no second interrupt
+        while ppu.mode == GpuMode::VBlank {
+            assert!(ppu.step(&vram, &oam).1.is_none());
+        }
+        assert_eq!(ppu.ly, 0);
+        assert_eq!(step_lines(&mut ppu, 1).1, 0);
+        assert_eq!(ppu.ly, 1);
+    }
+
+    #[test]
+    fn tall_sprites_test() {
+        let vram = vram_with_solid_tiles();
+        let mut oam = vec![0; 160];
+        // tile index LSB is ignored: tile 2 on top, tile 3 below
+        set_sprite(&mut oam, 0, 8, 16, 3, 0);
+        assert_eq!(render(TALL_SPRITES_ON, &vram, &oam, 0)[0], DmgColor::DarkGray);
+        assert_eq!(render(TALL_SPRITES_ON, &vram, &oam, 15)[0], DmgColor::Black);
+        assert_eq!(render(SPRITES_ON, &vram, &oam, 8)[0], DmgColor::White);
+
+        // flipped vertically over all 16 rows
+        set_sprite(&mut oam, 0, 8, 16, 2, 0x40);
+        assert_eq!(render(TALL_SPRITES_ON, &vram, &oam, 0)[0], DmgColor::Black);
+
+        // near the bottom of the Y range, must not overflow
+        set_sprite(&mut oam, 0, 8, 250, 2, 0);
+        assert_eq!(render(TALL_SPRITES_ON, &vram, &oam, 143)[0], DmgColor::White);
+    }
+
+    #[test]
+    fn sprite_priority_test() {
+        let vram = vram_with_solid_tiles();
+        let mut oam = vec![0; 160];
+        // lower X wins even with a higher OAM index
+        set_sprite(&mut oam, 0, 12, 16, 1, 0);
+        set_sprite(&mut oam, 1, 8, 16, 2, 0);
+        let line = render(SPRITES_ON, &vram, &oam, 0);
+        assert_eq!(line[5], DmgColor::DarkGray);
+        assert_eq!(line[9], DmgColor::LightGray);
+
+        // same X, lower OAM index wins
+        set_sprite(&mut oam, 1, 12, 16, 2, 0);
+        assert_eq!(render(SPRITES_ON, &vram, &oam, 0)[5], DmgColor::LightGray);
+    }
+
+    #[test]
+    fn ten_sprites_per_line_test() {
+        let vram = vram_with_solid_tiles();
+        let mut oam = vec![0; 160];
+        // sprites hidden off the left edge still count towards the limit
+        for i in 0..10 {
+            set_sprite(&mut oam, i, 0, 16, 1, 0);
+        }
+        set_sprite(&mut oam, 10, 8, 16, 1, 0);
+        assert_eq!(render(SPRITES_ON, &vram, &oam, 0)[0], DmgColor::White);
+        set_sprite(&mut oam, 0, 0, 40, 1, 0);
+        assert_eq!(render(SPRITES_ON, &vram, &oam, 0)[0], DmgColor::LightGray);
+    }
+
+    #[test]
+    fn bg_over_obj_test() {
+        let mut vram = vram_with_solid_tiles();
+        // BG uses tile 0, give its first row color 1 on the left half
+        vram[0] = 0xF0;
+        let mut oam = vec![0; 160];
+        set_sprite(&mut oam, 0, 8, 16, 3, 0x80);
+        set_sprite(&mut oam, 1, 16, 16, 3, 0x00);
+        let line = render(SPRITES_ON, &vram, &oam, 0);
+        assert_eq!(line[0], DmgColor::LightGray);
+        assert_eq!(line[4], DmgColor::Black);
+        assert_eq!(line[8], DmgColor::Black);
+    }
+
+    #[test]
+    fn sprite_clipping_test() {
+        let mut vram = vec![0; 0x2000];
+        // tile 1: right half color 1, row 6 color 3
+        for row in 0..8 {
+            vram[16 + row * 2] = 0x0F;
+        }
+        vram[16 + 12] = 0xFF;
+        vram[16 + 13] = 0xFF;
+        let mut oam = vec![0; 160];
+        set_sprite(&mut oam, 0, 4, 10, 1, 0);
+        let line = render(SPRITES_ON, &vram, &oam, 0);
+        assert_eq!(line[0], DmgColor::Black);
+        assert_eq!(line[3], DmgColor::Black);
+        assert_eq!(line[4], DmgColor::White);
+        let line = render(SPRITES_ON, &vram, &oam, 1);
+        assert_eq!(line[0], DmgColor::LightGray);
+        assert_eq!(line[4], DmgColor::White);
+    }
+
+    // color n of BG palette p is p * 4 + n, OBJ palette colors have bit 8 set on top
+    fn cgb_ppu(lcdc: u8) -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.cgb_mode = true;
+        ppu.lcdc = Lcdc::from_byte(lcdc);
+        ppu.bg_color_palettes.write_index(0x80);
+        ppu.obj_color_palettes.write_index(0x80);
+        for i in 0..32 {
+            ppu.bg_color_palettes.write_data(i);
+            ppu.bg_color_palettes.write_data(0);
+            ppu.obj_color_palettes.write_data(i);
+            ppu.obj_color_palettes.write_data(1);
+        }
+        ppu
+    }
+
+    fn cgb_line(ppu: &Ppu, ly: u8) -> Vec<u16> {
+        ppu.cgb_framebuffer[ly as usize * 160..(ly as usize + 1) * 160].to_vec()
+    }
+
+    // solid tiles in bank 0, tile 1 of bank 1 is solid color 2
+    fn cgb_vram() -> Vec<u8> {
+        let mut vram = vram_with_solid_tiles();
+        vram.resize(0x4000, 0);
+        for row in 0..8 {
+            vram[0x2000 + 16 + row * 2 + 1] = 0xFF;
+        }
+        vram
+    }
+
+    #[test]
+    fn color_palettes_test() {
+        let mut palettes = ColorPalettes::new();
+        palettes.write_index(0x80 | 10);
+        palettes.write_data(0x1F);
+        palettes.write_data(0x7C);
+        assert_eq!(palettes.read_index(), 0xC0 | 12);
+        assert_eq!(palettes.color(1, 1), 0x7C1F);
+
+        // without auto-increment the index stays
+        palettes.write_index(63);
+        palettes.write_data(0x12);
+        palettes.write_data(0x34);
+        assert_eq!(palettes.read_index(), 0x40 | 63);
+        assert_eq!(palettes.read_data(), 0x34);
+    }
+
+    #[test]
+    fn cgb_bg_attributes_test() {
+        let mut vram = cgb_vram();
+        // tile 4: leftmost column color 1, tile 5: top row color 1
+        for row in 0..8 {
+            vram[4 * 16 + row * 2] = 0x80;
+        }
+        vram[5 * 16] = 0xFF;
+        vram[0x1800..0x1804].copy_from_slice(&[1, 1, 4, 5]);
+        vram[0x3800..0x3804].copy_from_slice(&[0x05, BG_ATTR_BANK1 | 0x02, BG_ATTR_FLIP_X, BG_ATTR_FLIP_Y]);
+        let oam = vec![0; 160];
+        let mut ppu = cgb_ppu(0b1001_0001);
+        ppu.render_line(0, &vram, &oam);
+        ppu.render_line(7, &vram, &oam);
+
+        let line0 = cgb_line(&ppu, 0);
+        assert_eq!(line0[0], 5 * 4 + 1);
+        assert_eq!(line0[8], 2 * 4 + 2);
+        assert_eq!(line0[16], 0);
+        assert_eq!(line0[23], 1);
+        assert_eq!(line0[24], 0);
+        assert_eq!(cgb_line(&ppu, 7)[24], 1);
+        // the DMG framebuffer is left alone
+        assert_eq!(line(&ppu, 0)[0], DmgColor::White);
+    }
+
+    #[test]
+    fn cgb_sprite_priority_test() {
+        let mut vram = cgb_vram();
+        let mut oam = vec![0; 160];
+        // OAM order wins over X
+        set_sprite(&mut oam, 0, 12, 16, 1, 0x03);
+        set_sprite(&mut oam, 1, 8, 16, 2, 0x00);
+        // tile data from bank 1
+        set_sprite(&mut oam, 2, 40, 16, 1, 0x08);
+        let mut ppu = cgb_ppu(SPRITES_ON);
+        ppu.render_line(0, &vram, &oam);
+        let line0 = cgb_line(&ppu, 0);
+        assert_eq!(line0[0], 0x100 | 2);
+        assert_eq!(line0[4], 0x100 | (3 * 4 + 1));
+        assert_eq!(line0[32], 0x100 | 2);
+
+        // BG priority bit puts non-zero BG colors in front
+        vram[0x1800] = 3;
+        vram[0x3800] = BG_ATTR_PRIORITY;
+        ppu.render_line(0, &vram, &oam);
+        assert_eq!(cgb_line(&ppu, 0)[0], 3);
+
+        // unless LCDC bit 0 is clear, which also keeps the BG visible
+        ppu.lcdc.bg_window_priority = false;
+        vram[0x1801] = 3;
+        ppu.render_line(0, &vram, &oam);
+        assert_eq!(cgb_line(&ppu, 0)[0], 0x100 | 2);
+        assert_eq!(cgb_line(&ppu, 0)[15], 3);
+    }
+
+    #[test]
+    fn dmg_compat_mode_test() {
+        let mut vram = vram_with_solid_tiles();
+        vram[0x1800] = 1;
+        let mut oam = vec![0; 160];
+        set_sprite(&mut oam, 0, 16, 16, 3, 0x10);
+        let mut ppu = Ppu::new();
+        ppu.dmg_compat_mode = true;
+        ppu.lcdc = Lcdc::from_byte(SPRITES_ON);
+        // shades go through the DMG palettes first
+        ppu.bg_palette = DmgPalette::from_u8(0x1B);
+        ppu.obj1_palette = DmgPalette::from_u8(0xE4);
+        ppu.bg_color_palettes.set_palette(0, [1, 2, 3, 4]);
+        ppu.obj_color_palettes.set_palette(1, [5, 6, 7, 8]);
+        ppu.render_line(0, &vram, &oam);
+        assert_eq!(cgb_line(&ppu, 0)[0], 3);
+        assert_eq!(cgb_line(&ppu, 0)[8], 8);
+        assert_eq!(cgb_line(&ppu, 0)[16], 4);
+        assert_eq!(line(&ppu, 0)[0], DmgColor::DarkGray);
+        assert_eq!(ppu.framebuffer_layers[0], DmgLayer::Bg);
+        assert_eq!(ppu.framebuffer_layers[8], DmgLayer::Obj1);
+        assert!(ppu.color_output());
+    }
+}
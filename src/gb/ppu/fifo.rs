@@ -0,0 +1,421 @@
+use std::collections::VecDeque;
+
+use crate::gb::ppu::*;
+
+const VRAM_OFFSET: u16 = 0x8000;
+// the first tile fetched on every line is thrown away
+const FIRST_FETCH_DOTS: u8 = 6;
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+enum FetcherStep {
+    #[default]
+    Tile,
+    DataLo,
+    DataHi,
+    // waits until the BG FIFO is empty
+    Push,
+}
+
+#[derive(Copy, Clone, Default)]
+struct BgPixel {
+    color: u8,
+    // CGB tile attributes
+    attrs: u8,
+}
+
+#[derive(Copy, Clone, Default)]
+struct SpritePixel {
+    color: u8,
+    palette: u8,
+    low_priority: bool,
+    oam_idx: u8,
+}
+
+/// State of the pixel pipeline during mode 3 of a single line
+#[derive(Default)]
+pub struct PixelFifo {
+    bg: VecDeque<BgPixel>,
+    sprites: VecDeque<SpritePixel>,
+
+    step: FetcherStep,
+    step_dots: u8,
+    // tile column the fetcher works on, relative to the BG scroll or the window start
+    fetcher_x: u8,
+    tile_idx: u8,
+    tile_attrs: u8,
+    tile_lo: u8,
+    tile_hi: u8,
+    in_window: bool,
+
+    lcd_x: u8,
+    // pixels dropped from the start of the line, SCX % 8 or the hidden part of the window
+    discard: u8,
+    // dots the pipeline stands still for: the first fetch and sprite fetches
+    stall: u8,
+    pending_sprite: Option<OamEntry>,
+    // sprites on this line not fetched yet, in priority order
+    line_sprites: VecDeque<OamEntry>,
+    dots: u32,
+}
+
+impl PixelFifo {
+    /// Length of mode 3 so far
+    pub fn dots(&self) -> u32 {
+        self.dots
+    }
+}
+
+impl Ppu {
+    pub(super) fn fifo_start_line(&mut self, oam: &[u8]) {
+        if self.ly == self.w_y {
+            self.window_y_triggered = true;
+        }
+        let tile_height = if self.lcdc.tall_sprites { 16 } else { 8 };
+        // sprites are fetched as the LCD reaches them, CGB priority is sorted out when merging
+        let mut line_sprites = self.sprites_on_line(self.ly, tile_height, oam);
+        line_sprites.sort_by_key(|spr| spr.pos_x);
+        self.fifo = PixelFifo {
+            discard: self.sc_x % 8,
+            stall: FIRST_FETCH_DOTS,
+            line_sprites: line_sprites.into(),
+            ..PixelFifo::default()
+        };
+    }
+
+    /// Runs one dot of mode 3, returns true once the whole line got drawn
+    pub(super) fn fifo_dot(&mut self, vram: &[u8]) -> bool {
+        self.fifo.dots += 1;
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            if self.fifo.stall == 0 {
+                if let Some(spr) = self.fifo.pending_sprite.take() {
+                    self.merge_sprite(&spr, vram);
+                }
+            }
+            return false;
+        }
+
+        let lcd_x = self.fifo.lcd_x;
+        let sprite_hit = self.lcdc.sprites_enabled &&
+            self.fifo.line_sprites.front().is_some_and(|spr| spr.pos_x <= lcd_x + 8);
+        if sprite_hit {
+            // the sprite fetch waits for the BG fetcher to finish its tile
+            if self.fifo.step == FetcherStep::Push && !self.fifo.bg.is_empty() {
+                self.fifo.pending_sprite = self.fifo.line_sprites.pop_front();
+                self.fifo.stall = SPRITE_FETCH_DOTS;
+            } else {
+                self.fetcher_dot(vram);
+            }
+            return false;
+        }
+
+        // after a line where WX=166 showed the window, it covers the whole next line
+        let window_x_reached = self.window_fills_next_line || lcd_x as u16 + 7 >= self.w_x as u16;
+        let window_starts = !self.fifo.in_window &&
+            self.lcdc.window_enabled &&
+            self.window_y_triggered &&
+            self.w_x <= 166 &&
+            window_x_reached;
+        if window_starts {
+            // the fetcher restarts from the first window tile
+            self.fifo.in_window = true;
+            self.fifo.bg.clear();
+            self.fifo.step = FetcherStep::Tile;
+            self.fifo.step_dots = 0;
+            self.fifo.fetcher_x = 0;
+            self.fifo.discard = if lcd_x == 0 && !self.window_fills_next_line {
+                7u8.saturating_sub(self.w_x)
+            } else {
+                0
+            };
+        }
+
+        self.fetcher_dot(vram);
+        if let Some(bg) = self.fifo.bg.pop_front() {
+            if self.fifo.discard > 0 {
+                self.fifo.discard -= 1;
+                return false;
+            }
+            let spr = self.fifo.sprites.pop_front();
+            self.output_pixel(bg, spr);
+            self.fifo.lcd_x += 1;
+            if self.fifo.lcd_x == 160 {
+                if self.fifo.in_window {
+                    self.window_line += 1;
+                }
+                self.window_fills_next_line = self.fifo.in_window && self.w_x == 166;
+                return true;
+            }
+        }
+        false
+    }
+
+    // every step but the push takes 2 dots
+    fn fetcher_dot(&mut self, vram: &[u8]) {
+        if self.fifo.step == FetcherStep::Push {
+            if self.fifo.bg.is_empty() {
+                let (lo, hi) = (self.fifo.tile_lo, self.fifo.tile_hi);
+                let attrs = self.fifo.tile_attrs;
+                let flip_x = attrs & BG_ATTR_FLIP_X != 0;
+                self.fifo.bg.extend((0..8).map(|x| {
+                    let bit = if flip_x { x } else { 7 - x };
+                    BgPixel { color: (hi >> bit & 1) << 1 | lo >> bit & 1, attrs }
+                }));
+                self.fifo.fetcher_x = self.fifo.fetcher_x.wrapping_add(1);
+                self.fifo.step = FetcherStep::Tile;
+            }
+            return;
+        }
+        self.fifo.step_dots += 1;
+        if self.fifo.step_dots < 2 {
+            return;
+        }
+        self.fifo.step_dots = 0;
+        let ly = self.ly;
+        let (row, tilemap1) = if self.fifo.in_window {
+            (self.window_line, self.lcdc.window_tilemap_select1)
+        } else {
+            (ly.wrapping_add(self.sc_y), self.lcdc.bg_tilemap_select1)
+        };
+        match self.fifo.step {
+            FetcherStep::Tile => {
+                let tilemap_x = if self.fifo.in_window {
+                    self.fifo.fetcher_x
+                } else {
+                    (self.sc_x / 8).wrapping_add(self.fifo.fetcher_x)
+                } % 32;
+                let tilemap_start_addr: u16 = if tilemap1 { 0x9C00 } else { 0x9800 };
+                let tilemap_idx = (row / 8) as u16 * 32 + tilemap_x as u16;
+                let addr = (tilemap_start_addr - VRAM_OFFSET + tilemap_idx) as usize;
+                self.fifo.tile_idx = vram[addr];
+                self.fifo.tile_attrs = if self.cgb_mode { vram[VRAM_BANK_SIZE + addr] } else { 0 };
+                self.fifo.step = FetcherStep::DataLo;
+            }
+            FetcherStep::DataLo | FetcherStep::DataHi => {
+                let attrs = self.fifo.tile_attrs;
+                let y_in_tile = if attrs & BG_ATTR_FLIP_Y != 0 { 7 - row % 8 } else { row % 8 };
+                let bank_offset = if attrs & BG_ATTR_BANK1 != 0 { VRAM_BANK_SIZE } else { 0 };
+                let tile_addr = get_tile_addr(self.fifo.tile_idx, self.lcdc.bg_window_tile_data_select1, vram);
+                let addr = bank_offset + (tile_addr - VRAM_OFFSET + y_in_tile as u16 * 2) as usize;
+                if self.fifo.step == FetcherStep::DataLo {
+                    self.fifo.tile_lo = vram[addr];
+                    self.fifo.step = FetcherStep::DataHi;
+                } else {
+                    self.fifo.tile_hi = vram[addr + 1];
+                    self.fifo.step = FetcherStep::Push;
+                }
+            }
+            FetcherStep::Push => {}
+        }
+    }
+
+    // on DMG pixels already in the sprite FIFO belong to sprites with higher priority,
+    // on CGB a sprite earlier in OAM takes over the non-transparent pixels
+    fn merge_sprite(&mut self, spr: &OamEntry, vram: &[u8]) {
+        let tile_height = if self.lcdc.tall_sprites { 16 } else { 8 };
+        let (lo, hi) = sprite_tile_row(spr, self.ly, tile_height, self.cgb_mode, vram);
+        let palette = self.sprite_palette(spr);
+        // sprites partially off the left edge lose their first pixels
+        let hidden = 8u8.saturating_sub(spr.pos_x);
+        while self.fifo.sprites.len() < 8 {
+            self.fifo.sprites.push_back(SpritePixel::default());
+        }
+        for x in hidden..8 {
+            let bit = if spr.flip_x { x } else { 7 - x };
+            let color = (hi >> bit & 1) << 1 | lo >> bit & 1;
+            let slot = &mut self.fifo.sprites[(x - hidden) as usize];
+            let takes_over = self.cgb_mode && color != 0 && spr.oam_idx < slot.oam_idx;
+            if slot.color == 0 || takes_over {
+                *slot = SpritePixel { color, palette, low_priority: spr.low_priority, oam_idx: spr.oam_idx };
+            }
+        }
+    }
+
+    // palettes and LCDC are read as each pixel is pushed to the LCD
+    fn output_pixel(&mut self, bg: BgPixel, spr: Option<SpritePixel>) {
+        // on DMG, LCDC bit 0 blanks both the BG and the window
+        let bg_color = if self.cgb_mode || self.lcdc.bg_window_priority { bg.color } else { 0 };
+        let (ly, x) = (self.ly, self.fifo.lcd_x);
+        match spr {
+            Some(spr) if spr.color != 0 && self.lcdc.sprites_enabled &&
+                !self.bg_over_sprite(bg_color, bg.attrs, spr.low_priority) => {
+                self.put_sprite_pixel(ly, x, spr.color, spr.palette)
+            }
+            _ => self.put_bg_pixel(ly, x, bg_color, bg.attrs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LCD_ON: u8 = 0b1001_0011;
+
+    fn new_ppu(renderer: Renderer) -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.renderer = renderer;
+        ppu.lcdc = Lcdc::from_byte(LCD_ON);
+        ppu.bg_palette = DmgPalette::from_u8(0xE4);
+        ppu.obj0_palette = DmgPalette::from_u8(0xE4);
+        ppu.obj1_palette = DmgPalette::from_u8(0x1B);
+        ppu
+    }
+
+    // a mix of tiles, a window and sprites covering the first lines
+    fn scene() -> (Vec<u8>, Vec<u8>) {
+        let mut vram = vec![0; 0x2000];
+        for (i, byte) in vram[..0x100].iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(37) ^ 0x5A;
+        }
+        for i in 0..0x800 {
+            vram[0x1800 + i] = (i % 13) as u8;
+        }
+        let mut oam = vec![0; 160];
+        let sprites = [(3, 16, 1, 0x00), (40, 18, 2, 0x80), (44, 16, 3, 0x30), (100, 12, 4, 0x60), (160, 16, 5, 0)];
+        for (i, &(x, y, tile, flags)) in sprites.iter().enumerate() {
+            oam[i * 4..i * 4 + 4].copy_from_slice(&[y, x, tile, flags]);
+        }
+        (vram, oam)
+    }
+
+    // runs the PPU from the start of a line until it reaches HBlank
+    fn mode3_dots(ppu: &mut Ppu, vram: &[u8], oam: &[u8]) -> u32 {
+        while ppu.mode != GpuMode::OamAccess {
+            ppu.step(vram, oam);
+        }
+        while ppu.mode != GpuMode::HBlank {
+            ppu.step(vram, oam);
+        }
+        ppu.fifo.dots()
+    }
+
+    #[test]
+    fn same_output_as_scanline_renderer() {
+        let (vram, oam) = scene();
+        // WX=166 shows one window pixel, then the window covers the whole next lines
+        for &w_x in [87, 166].iter() {
+            let mut scanline = new_ppu(Renderer::Scanline);
+            let mut fifo = new_ppu(Renderer::Fifo);
+            for ppu in [&mut scanline, &mut fifo].iter_mut() {
+                ppu.lcdc = Lcdc::from_byte(LCD_ON | 0b0110_0000);
+                ppu.sc_x = 5;
+                ppu.sc_y = 3;
+                ppu.w_x = w_x;
+                ppu.w_y = 4;
+                for _ in 0..114 * 10 {
+                    ppu.step(&vram, &oam);
+                }
+            }
+            assert!(scanline.framebuffer[..160 * 9] == fifo.framebuffer[..160 * 9], "WX={}", w_x);
+        }
+    }
+
+    #[test]
+    fn cgb_same_output_as_scanline_renderer() {
+        let (mut vram, mut oam) = scene();
+        vram.resize(0x4000, 0);
+        for (i, byte) in vram[0x2000..0x2100].iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(91) ^ 0xA5;
+        }
+        // every combination of palette, bank, flips and priority
+        for i in 0..0x800 {
+            vram[0x3800 + i] = (i as u8).wrapping_mul(29) & 0b1110_1111;
+        }
+        for i in 0..5 {
+            oam[i * 4 + 3] |= (i as u8 * 3) & 0b1111;
+        }
+        let mut scanline = new_ppu(Renderer::Scanline);
+        let mut fifo = new_ppu(Renderer::Fifo);
+        for ppu in [&mut scanline, &mut fifo].iter_mut() {
+            ppu.cgb_mode = true;
+            ppu.lcdc = Lcdc::from_byte(LCD_ON | 0b0110_0000);
+            ppu.bg_color_palettes.write_index(0x80);
+            ppu.obj_color_palettes.write_index(0x80);
+            for i in 0..64 {
+                ppu.bg_color_palettes.write_data(i);
+                ppu.obj_color_palettes.write_data(!i);
+            }
+            ppu.sc_x = 5;
+            ppu.sc_y = 3;
+            ppu.w_x = 87;
+            ppu.w_y = 4;
+            for _ in 0..114 * 10 {
+                ppu.step(&vram, &oam);
+            }
+        }
+        assert!(scanline.cgb_framebuffer[..160 * 9] == fifo.cgb_framebuffer[..160 * 9]);
+    }
+
+    #[test]
+    fn mode3_length_test() {
+        let (vram, mut oam) = scene();
+        oam.iter_mut().for_each(|b| *b = 0);
+
+        let mut ppu = new_ppu(Renderer::Fifo);
+        assert_eq!(mode3_dots(&mut ppu, &vram, &oam), 172);
+
+        // fine scroll discards pixels
+        let mut ppu = new_ppu(Renderer::Fifo);
+        ppu.sc_x = 3;
+        assert_eq!(mode3_dots(&mut ppu, &vram, &oam), 175);
+
+        // restarting the fetcher for the window
+        let mut ppu = new_ppu(Renderer::Fifo);
+        ppu.lcdc = Lcdc::from_byte(LCD_ON | 0b0010_0000);
+        ppu.w_x = 87;
+        let dots = mode3_dots(&mut ppu, &vram, &oam);
+        assert!(dots >= 172 + 6, "{}", dots);
+
+        // every sprite stalls the pipeline
+        let mut ppu = new_ppu(Renderer::Fifo);
+        oam[..4].copy_from_slice(&[16, 50, 0, 0]);
+        let one_sprite = mode3_dots(&mut ppu, &vram, &oam);
+        assert!((172 + 6..=172 + 12).contains(&one_sprite), "{}", one_sprite);
+        let mut ppu = new_ppu(Renderer::Fifo);
+        oam[4..8].copy_from_slice(&[16, 90, 0, 0]);
+        assert!(mode3_dots(&mut ppu, &vram, &oam) > one_sprite);
+    }
+
+    #[test]
+    fn stat_reflects_mode3_length() {
+        let (vram, oam) = scene();
+        let mut ppu = new_ppu(Renderer::Fifo);
+        ppu.sc_x = 7;
+        while ppu.mode != GpuMode::VramAccess {
+            ppu.step(&vram, &oam);
+        }
+        let mut steps = 0;
+        while ppu.read_lcdstat() & 0b11 == 3 {
+            ppu.step(&vram, &oam);
+            steps += 1;
+        }
+        assert!(steps * 4 > 172 + 7);
+        // the whole line still takes 456 clocks
+        while ppu.mode != GpuMode::OamAccess {
+            ppu.step(&vram, &oam);
+        }
+        assert_eq!(ppu.ly, 1);
+    }
+
+    #[test]
+    fn mid_line_palette_change() {
+        let vram = vec![0xFF; 0x2000];
+        let oam = vec![0; 160];
+        let mut ppu = new_ppu(Renderer::Fifo);
+        while ppu.mode != GpuMode::VramAccess {
+            ppu.step(&vram, &oam);
+        }
+        // 20 steps are 80 dots, pixels up to about 68 are already out
+        for _ in 0..20 {
+            ppu.step(&vram, &oam);
+        }
+        ppu.bg_palette = DmgPalette::from_u8(0x00);
+        while ppu.mode != GpuMode::HBlank {
+            ppu.step(&vram, &oam);
+        }
+        assert_eq!(ppu.framebuffer[0], DmgColor::Black);
+        assert_eq!(ppu.framebuffer[159], DmgColor::White);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::gb::mmu::Mmu;
+
+const SEARCHED_RANGES: [(u16, u16); 2] = [
+    (0xC000, 0xDFFF), // work RAM
+    (0xFF80, 0xFFFE), // high RAM
+];
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Width {
+    Byte,
+    Word,
+}
+
+impl Width {
+    pub fn max_value(self) -> u16 {
+        match self {
+            Width::Byte => 0xFF,
+            Width::Word => 0xFFFF,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Filter {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    Value(u16),
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Candidate {
+    pub addr: u16,
+    pub prev: u16,
+}
+
+/// Narrows down the set of RAM addresses by comparing their values between snapshots.
+pub struct RamSearch {
+    width: Width,
+    candidates: Vec<Candidate>,
+}
+
+impl RamSearch {
+    pub fn new() -> RamSearch {
+        RamSearch {
+            width: Width::Byte,
+            candidates: vec![],
+        }
+    }
+
+    /// Takes a snapshot of RAM and makes every address a candidate again
+    pub fn reset(&mut self, mmu: &Mmu, width: Width) {
+        self.width = width;
+        self.candidates = SEARCHED_RANGES.iter()
+            .flat_map(|&(from, to)| {
+                let last = if width == Width::Word { to - 1 } else { to };
+                from..=last
+            })
+            .map(|addr| Candidate { addr, prev: read(mmu, addr, width) })
+            .collect();
+    }
+
+    /// Keeps only candidates matching the filter and updates their snapshot values
+    pub fn filter(&mut self, mmu: &Mmu, filter: Filter) {
+        let width = self.width;
+        self.candidates.retain_mut(|c| {
+            let val = read(mmu, c.addr, width);
+            let keep = match filter {
+                Filter::Equal => val == c.prev,
+                Filter::Changed => val != c.prev,
+                Filter::Increased => val > c.prev,
+                Filter::Decreased => val < c.prev,
+                Filter::Value(n) => val == n,
+            };
+            c.prev = val;
+            keep
+        });
+    }
+
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    pub fn width(&self) -> Width {
+        self.width
+    }
+}
+
+impl Default for RamSearch {
+    fn default() -> Self {
+        RamSearch::new()
+    }
+}
+
+fn read(mmu: &Mmu, addr: u16, width: Width) -> u16 {
+    match width {
+        Width::Byte => mmu.read_byte(addr) as u16,
+        Width::Word => mmu.read_word(addr),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gb::joypad::Joypad;
+    use crate::gb::mbc::NoMbc;
+    use crate::gb::ppu::Ppu;
+    use crate::gb::timer::Timer;
+
+    fn mmu() -> Mmu {
+        let cart = Box::new(NoMbc::new(vec![0; 0x8000]));
+        Mmu::new(None, cart, Joypad::new(), Timer::new(), Ppu::new())
+    }
+
+    #[test]
+    fn byte_search_test() {
+        let mut mmu = mmu();
+        let mut search = RamSearch::new();
+        mmu.write_byte(3, 0xC123);
+        mmu.write_byte(3, 0xFF90);
+        search.reset(&mmu, Width::Byte);
+        assert_eq!(search.candidates().len(), 0x2000 + 0x7F);
+
+        mmu.write_byte(2, 0xC123);
+        mmu.write_byte(2, 0xFF90);
+        search.filter(&mmu, Filter::Decreased);
+        assert_eq!(search.candidates().len(), 2);
+
+        mmu.write_byte(1, 0xC123);
+        search.filter(&mmu, Filter::Changed);
+        assert_eq!(search.candidates(), &[Candidate { addr: 0xC123, prev: 1 }]);
+
+        search.filter(&mmu, Filter::Value(1));
+        assert_eq!(search.candidates().len(), 1);
+        assert_eq!(search.width().max_value(), 0xFF);
+    }
+
+    #[test]
+    fn word_search_test() {
+        let mut mmu = mmu();
+        let mut search = RamSearch::new();
+        search.reset(&mmu, Width::Word);
+        mmu.write_word(0x1234, 0xD000);
+        search.filter(&mmu, Filter::Value(0x1234));
+        assert_eq!(search.candidates(), &[Candidate { addr: 0xD000, prev: 0x1234 }]);
+
+        mmu.write_word(0x1300, 0xD000);
+        search.filter(&mmu, Filter::Increased);
+        assert_eq!(search.candidates().len(), 1);
+        search.filter(&mmu, Filter::Equal);
+        assert_eq!(search.candidates().len(), 1);
+    }
+}
@@ -0,0 +1,536 @@
+use crate::gb::vram::DmgColor;
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+// position of the Game Boy screen inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+pub const MLT_REQ: u8 = 0x11;
+
+// cream to dark brown, what the SGB shows until a game sets its own palettes
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+/// Decodes the packets sent through P14 and P15: a reset pulse with both lines low, then
+/// 128 bits, P14 low for a 0 and P15 low for a 1, each followed by both lines high, and a
+/// 0 stop bit. The first byte holds the command and how many packets it's made of.
+#[derive(Debug, Default)]
+pub struct PacketReader {
+    receiving: bool,
+    bits: usize,
+    packet: [u8; PACKET_SIZE],
+    command: Vec<u8>,
+    // P14/P15 as of the last write
+    lines: u8,
+}
+
+impl PacketReader {
+    /// Returns the whole command once its last packet is in
+    pub fn write(&mut self, p1: u8) -> Option<Vec<u8>> {
+        let lines = p1 & 0x30;
+        let prev_lines = std::mem::replace(&mut self.lines, lines);
+        match lines {
+            0x00 => {
+                self.receiving = true;
+                self.bits = 0;
+                self.packet = [0; PACKET_SIZE];
+                None
+            }
+            0x10 | 0x20 if self.receiving && prev_lines == 0x30 => self.receive_bit(lines == 0x10),
+            _ => None,
+        }
+    }
+
+    fn receive_bit(&mut self, bit: bool) -> Option<Vec<u8>> {
+        if self.bits < PACKET_SIZE * 8 {
+            self.packet[self.bits / 8] |= (bit as u8) << (self.bits % 8);
+            self.bits += 1;
+            return None;
+        }
+        // stop bit
+        self.receiving = false;
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0b111).max(1) as usize;
+        if self.command.len() < packets * PACKET_SIZE {
+            return None;
+        }
+        Some(std::mem::take(&mut self.command))
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum Mask {
+    None,
+    // keeps showing the screen as it was when the mask was set
+    Freeze,
+    Black,
+    Color0,
+}
+
+/// Super Game Boy state: the palettes applied to 8x8 areas of the screen and the border
+/// drawn around it. Games send commands through the joypad register.
+pub struct Sgb {
+    // color 0 is shared by all 4 palettes
+    palettes: [[u16; 4]; 4],
+    // palette of each 8x8 area of the screen
+    attr_map: [u8; 20 * 18],
+    // 512 palettes sent by PAL_TRN, picked from by PAL_SET
+    system_palettes: Vec<u16>,
+    // 45 attribute maps sent by ATTR_TRN, 2 bits per area
+    attr_files: Vec<u8>,
+    // 256 SNES 4bpp tiles and the 32x28 border tilemap using them
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    // border palettes 4-7
+    border_palettes: [[u16; 16]; 4],
+    mask: Mask,
+    frozen: Vec<DmgColor>,
+    screen: Vec<u16>,
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            palettes: [DEFAULT_PALETTE; 4],
+            attr_map: [0; 20 * 18],
+            system_palettes: vec![0; 512 * 4],
+            attr_files: vec![0; 45 * 90],
+            border_tiles: vec![0; 256 * 32],
+            border_map: vec![0; 32 * 28],
+            border_palettes: [[0; 16]; 4],
+            mask: Mask::None,
+            frozen: vec![DmgColor::White; 160 * 144],
+            screen: vec![0; SGB_WIDTH * SGB_HEIGHT],
+        }
+    }
+
+    /// The *_TRN commands copy 4KB from what's currently on screen
+    pub fn handle_command(&mut self, data: &[u8], framebuffer: &[DmgColor]) {
+        let word = |i: usize| data[i] as u16 | (data[i + 1] as u16) << 8;
+        match data[0] >> 3 {
+            // PAL01, PAL23, PAL03, PAL12
+            0x00 => self.set_palette_pair(0, 1, data),
+            0x01 => self.set_palette_pair(2, 3, data),
+            0x02 => self.set_palette_pair(0, 3, data),
+            0x03 => self.set_palette_pair(1, 2, data),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            // PAL_SET
+            0x0A => {
+                for i in 0..4 {
+                    let idx = (word(1 + i * 2) & 0x1FF) as usize;
+                    self.palettes[i].copy_from_slice(&self.system_palettes[idx * 4..idx * 4 + 4]);
+                }
+                self.share_color0(self.palettes[0][0]);
+                if data[9] & 0x80 != 0 {
+                    self.apply_attr_file(data[9] & 0x3F);
+                }
+                if data[9] & 0x40 != 0 {
+                    self.mask = Mask::None;
+                }
+            }
+            // PAL_TRN
+            0x0B => {
+                let vram = screen_tile_data(framebuffer);
+                for (i, color) in self.system_palettes.iter_mut().enumerate() {
+                    *color = vram[i * 2] as u16 | (vram[i * 2 + 1] as u16) << 8;
+                }
+            }
+            // CHR_TRN, tiles 0x00-0x7F or 0x80-0xFF
+            0x13 => {
+                let start = (data[1] & 1) as usize * 128 * 32;
+                self.border_tiles[start..start + 128 * 32].copy_from_slice(&screen_tile_data(framebuffer));
+            }
+            // PCT_TRN
+            0x14 => {
+                let vram = screen_tile_data(framebuffer);
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = vram[i * 2] as u16 | (vram[i * 2 + 1] as u16) << 8;
+                }
+                for (i, color) in self.border_palettes.iter_mut().flatten().enumerate() {
+                    *color = vram[0x800 + i * 2] as u16 | (vram[0x800 + i * 2 + 1] as u16) << 8;
+                }
+            }
+            // ATTR_TRN
+            0x15 => {
+                let len = self.attr_files.len();
+                self.attr_files.copy_from_slice(&screen_tile_data(framebuffer)[..len]);
+            }
+            // ATTR_SET
+            0x16 => {
+                self.apply_attr_file(data[1] & 0x3F);
+                if data[1] & 0x40 != 0 {
+                    self.mask = Mask::None;
+                }
+            }
+            // MASK_EN
+            0x17 => {
+                self.mask = match data[1] & 0b11 {
+                    0 => Mask::None,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                };
+                if self.mask == Mask::Freeze {
+                    self.frozen.copy_from_slice(framebuffer);
+                }
+            }
+            // sound, SNES code upload and the rest are not emulated
+            _ => {}
+        }
+    }
+
+    fn share_color0(&mut self, color: u16) {
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color;
+        }
+    }
+
+    fn set_palette_pair(&mut self, a: usize, b: usize, data: &[u8]) {
+        let color = |i: usize| data[1 + i * 2] as u16 | (data[2 + i * 2] as u16) << 8;
+        self.share_color0(color(0));
+        for i in 1..4 {
+            self.palettes[a][i] = color(i);
+            self.palettes[b][i] = color(i + 3);
+        }
+    }
+
+    fn set_attr(&mut self, x: usize, y: usize, palette: u8) {
+        self.attr_map[y * 20 + x] = palette & 0b11;
+    }
+
+    // up to 18 areas, each colors the inside, the border and/or the outside of a rectangle
+    fn attr_blk(&mut self, data: &[u8]) {
+        let sets = (data[1] as usize).min(18);
+        for set in data[2..].chunks(6).take(sets).filter(|set| set.len() == 6) {
+            let (control, palettes) = (set[0] & 0b111, set[1]);
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+            let inside = palettes & 0b11;
+            let border = (palettes >> 2) & 0b11;
+            let outside = (palettes >> 4) & 0b11;
+            // with only the inside or the outside given, the border goes along with it
+            let border = match control {
+                0b001 => Some(inside),
+                0b100 => Some(outside),
+                c if c & 0b010 != 0 => Some(border),
+                _ => None,
+            };
+            for y in 0..18 {
+                for x in 0..20 {
+                    let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let on_edge = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = if on_edge {
+                        border
+                    } else if within {
+                        Some(inside).filter(|_| control & 0b001 != 0)
+                    } else {
+                        Some(outside).filter(|_| control & 0b100 != 0)
+                    };
+                    if let Some(palette) = palette {
+                        self.set_attr(x, y, palette);
+                    }
+                }
+            }
+        }
+    }
+
+    // whole rows or columns, bit 7 set for a row
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let n = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0b11;
+            if line & 0x80 != 0 {
+                for x in 0..20 {
+                    self.set_attr(x, n.min(17), palette);
+                }
+            } else {
+                for y in 0..18 {
+                    self.set_attr(n.min(19), y, palette);
+                }
+            }
+        }
+    }
+
+    // splits the screen in two at a row or a column, the line itself gets its own palette
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = (data[1] >> 2) & 0b11;
+        let on_line = (data[1] >> 4) & 0b11;
+        let by_row = data[1] & 0x40 != 0;
+        let at = data[2] as usize;
+        for y in 0..18 {
+            for x in 0..20 {
+                let pos = if by_row { y } else { x };
+                let palette = match pos {
+                    p if p < at => before,
+                    p if p == at => on_line,
+                    _ => after,
+                };
+                self.set_attr(x, y, palette);
+            }
+        }
+    }
+
+    // a palette for each area, 4 per byte, starting at X,Y and going right or down
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = ((data[1] as usize).min(19), (data[2] as usize).min(17));
+        let count = (data[3] as usize | (data[4] as usize) << 8).min(20 * 18);
+        let vertical = data[5] & 1 != 0;
+        for i in 0..count {
+            let byte = match data.get(6 + i / 4) {
+                Some(&b) => b,
+                None => break,
+            };
+            self.set_attr(x, y, byte >> (6 - (i % 4) * 2));
+            if vertical {
+                y += 1;
+                if y == 18 {
+                    y = 0;
+                    x = (x + 1) % 20;
+                }
+            } else {
+                x += 1;
+                if x == 20 {
+                    x = 0;
+                    y = (y + 1) % 18;
+                }
+            }
+        }
+    }
+
+    fn apply_attr_file(&mut self, file: u8) {
+        if file as usize >= 45 {
+            return;
+        }
+        let start = file as usize * 90;
+        for i in 0..20 * 18 {
+            let byte = self.attr_files[start + i / 4];
+            self.attr_map[i] = byte >> (6 - (i % 4) * 2) & 0b11;
+        }
+    }
+
+    /// Draws the border and the colored Game Boy screen inside it, as 15-bit colors
+    pub fn render(&mut self, framebuffer: &[DmgColor]) -> &[u16] {
+        let backdrop = self.palettes[0][0];
+        for y in 0..SGB_HEIGHT {
+            for x in 0..SGB_WIDTH {
+                let entry = self.border_map[(y / 8) * 32 + x / 8];
+                let tile = (entry & 0xFF) as usize;
+                let palette = ((entry >> 10) & 0b11) as usize;
+                let col = if entry & 0x4000 != 0 { 7 - x % 8 } else { x % 8 };
+                let row = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+                let color = snes_tile_pixel(&self.border_tiles[tile * 32..tile * 32 + 32], col, row);
+                self.screen[y * SGB_WIDTH + x] = if color == 0 {
+                    backdrop
+                } else {
+                    self.border_palettes[palette][color as usize]
+                };
+            }
+        }
+        let shown = if self.mask == Mask::Freeze { &self.frozen[..] } else { framebuffer };
+        for y in 0..144 {
+            for x in 0..160 {
+                let palette = &self.palettes[self.attr_map[(y / 8) * 20 + x / 8] as usize];
+                let color = match self.mask {
+                    Mask::Black => 0,
+                    Mask::Color0 => backdrop,
+                    _ => palette[shown[y * 160 + x].shade() as usize],
+                };
+                self.screen[(SCREEN_Y + y) * SGB_WIDTH + SCREEN_X + x] = color;
+            }
+        }
+        &self.screen
+    }
+}
+
+/// The SGB reads the screen as 2bpp tiles, 20 per row from the top left, 256 tiles in total.
+/// Games show the data with the identity palette to send it.
+fn screen_tile_data(framebuffer: &[DmgColor]) -> Vec<u8> {
+    let mut data = vec![0; 4096];
+    for tile in 0..256 {
+        let (tile_x, tile_y) = (tile % 20, tile / 20);
+        for row in 0..8 {
+            let line = (tile_y * 8 + row) * 160 + tile_x * 8;
+            let (mut lo, mut hi) = (0, 0);
+            for col in 0..8 {
+                let shade = framebuffer[line + col].shade();
+                lo |= (shade & 1) << (7 - col);
+                hi |= (shade >> 1) << (7 - col);
+            }
+            data[tile * 16 + row * 2] = lo;
+            data[tile * 16 + row * 2 + 1] = hi;
+        }
+    }
+    data
+}
+
+// SNES 4bpp tiles keep bitplanes 0 and 1 of each row in the first 16 bytes, 2 and 3 after
+fn snes_tile_pixel(tile: &[u8], x: usize, y: usize) -> u8 {
+    let bit = |byte: u8| (byte >> (7 - x)) & 1;
+    bit(tile[y * 2]) | bit(tile[y * 2 + 1]) << 1 | bit(tile[16 + y * 2]) << 2 | bit(tile[16 + y * 2 + 1]) << 3
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gb::joypad::Joypad;
+
+    // P1 writes sending a packet: reset pulse, 128 bits and the stop bit
+    fn packet_writes(packet: &[u8; 16]) -> Vec<u8> {
+        let mut writes = vec![0x00, 0x30];
+        for i in 0..129 {
+            let bit = if i < 128 { packet[i / 8] >> (i % 8) & 1 } else { 0 };
+            writes.push(if bit == 1 { 0x10 } else { 0x20 });
+            writes.push(0x30);
+        }
+        writes
+    }
+
+    fn send_packet(reader: &mut PacketReader, packet: &[u8; 16]) -> Option<Vec<u8>> {
+        packet_writes(packet).into_iter().filter_map(|p1| reader.write(p1)).last()
+    }
+
+    fn command(bytes: &[u8]) -> Vec<u8> {
+        let mut data = bytes.to_vec();
+        data.resize(PACKET_SIZE, 0);
+        data
+    }
+
+    #[test]
+    fn packet_reader_test() {
+        let mut reader = PacketReader::default();
+        let mut first = [0; 16];
+        first[0] = 0x04 << 3 | 2;
+        first[15] = 0xAB;
+        let second = [0x5A; 16];
+        assert_eq!(send_packet(&mut reader, &first), None);
+        let data = send_packet(&mut reader, &second).unwrap();
+        assert_eq!(data.len(), 32);
+        assert_eq!(data[15], 0xAB);
+        assert_eq!(data[16], 0x5A);
+
+        // a repeated write doesn't count as another bit
+        let mut reader = PacketReader::default();
+        reader.write(0x00);
+        reader.write(0x30);
+        reader.write(0x10);
+        reader.write(0x10);
+        assert_eq!(reader.bits, 1);
+    }
+
+    #[test]
+    fn multiplayer_test() {
+        let mut joypad = Joypad::new();
+        joypad.enable_sgb();
+        let mut packet = [0; 16];
+        packet[0] = MLT_REQ << 3 | 1;
+        packet[1] = 1;
+        for p1 in packet_writes(&packet) {
+            joypad.write_byte(p1);
+        }
+        assert_eq!(joypad.take_sgb_command(), None);
+        // the first controller is read until P15 goes low and back high
+        assert_eq!(joypad.read_byte(), 0xFF);
+        joypad.write_byte(0x10);
+        assert_eq!(joypad.read_byte() & 0x0F, 0x0F);
+        joypad.write_byte(0x30);
+        assert_eq!(joypad.read_byte(), 0xFE);
+        joypad.write_byte(0x10);
+        joypad.write_byte(0x30);
+        assert_eq!(joypad.read_byte(), 0xFF);
+    }
+
+    #[test]
+    fn palettes_and_attributes_test() {
+        let mut sgb = Sgb::new();
+        let framebuffer = vec![DmgColor::DarkGray; 160 * 144];
+        // PAL23: color 0, palette 2 colors 1-3, palette 3 colors 1-3
+        sgb.handle_command(&command(&[0x01 << 3 | 1, 1, 0, 2, 0, 3, 0, 4, 0, 5, 0, 6, 0, 7, 0]), &framebuffer);
+        assert_eq!(sgb.palettes[0][0], 1);
+        assert_eq!(sgb.palettes[2], [1, 2, 3, 4]);
+        assert_eq!(sgb.palettes[3], [1, 5, 6, 7]);
+
+        // ATTR_BLK: palette 2 inside and on the border of (1,1)-(3,3), 3 outside
+        sgb.handle_command(&command(&[0x04 << 3 | 1, 1, 0b111, 0b11_10_10, 1, 1, 3, 3]), &framebuffer);
+        assert_eq!(sgb.attr_map[0], 3);
+        assert_eq!(sgb.attr_map[20 + 1], 2);
+        assert_eq!(sgb.attr_map[2 * 20 + 2], 2);
+        assert_eq!(sgb.attr_map[2 * 20 + 4], 3);
+        // with only the inside given the border goes along with it, the outside stays
+        sgb.handle_command(&command(&[0x04 << 3 | 1, 1, 0b001, 0b00_00_01, 1, 1, 3, 3]), &framebuffer);
+        assert_eq!(sgb.attr_map[20 + 1], 1);
+        assert_eq!(sgb.attr_map[2 * 20 + 2], 1);
+        assert_eq!(sgb.attr_map[0], 3);
+        sgb.handle_command(&command(&[0x04 << 3 | 1, 1, 0b111, 0b11_10_10, 1, 1, 3, 3]), &framebuffer);
+
+        let screen = sgb.render(&framebuffer);
+        assert_eq!(screen[SCREEN_Y * SGB_WIDTH + SCREEN_X], 6);
+        assert_eq!(screen[(SCREEN_Y + 8) * SGB_WIDTH + SCREEN_X + 8], 3);
+        // the border is transparent, showing color 0
+        assert_eq!(screen[0], 1);
+    }
+
+    #[test]
+    fn attr_lin_div_chr_test() {
+        let mut sgb = Sgb::new();
+        let framebuffer = vec![DmgColor::White; 160 * 144];
+        // ATTR_DIV: left of column 5 palette 1, on it 2, right of it 3
+        sgb.handle_command(&command(&[0x06 << 3 | 1, 0b10_01_11, 5]), &framebuffer);
+        assert_eq!(&sgb.attr_map[4..7], &[1, 2, 3]);
+        // ATTR_LIN: row 2 palette 0, column 0 palette 2
+        sgb.handle_command(&command(&[0x05 << 3 | 1, 2, 0x80 | 2, 0x40]), &framebuffer);
+        assert_eq!(sgb.attr_map[2 * 20 + 10], 0);
+        assert_eq!(sgb.attr_map[3 * 20], 2);
+        // ATTR_CHR: 3 areas going down from (19,17), wrapping to the next column
+        sgb.handle_command(&command(&[0x07 << 3 | 1, 19, 16, 3, 0, 1, 0b01_10_11_00]), &framebuffer);
+        assert_eq!(sgb.attr_map[16 * 20 + 19], 1);
+        assert_eq!(sgb.attr_map[17 * 20 + 19], 2);
+        assert_eq!(sgb.attr_map[0], 3);
+    }
+
+    #[test]
+    fn border_transfer_test() {
+        let mut sgb = Sgb::new();
+        // the first tiles on screen: tile 0 row 0 has all 4bpp bitplane 0 bits set
+        let mut framebuffer = vec![DmgColor::White; 160 * 144];
+        framebuffer[..8].fill(DmgColor::LightGray);
+        sgb.handle_command(&command(&[0x13 << 3 | 1, 0]), &framebuffer);
+        assert_eq!(snes_tile_pixel(&sgb.border_tiles[..32], 0, 0), 1);
+
+        // PCT_TRN: the border palettes start at 0x800, which is tile 128 on screen
+        let mut framebuffer = vec![DmgColor::White; 160 * 144];
+        let (tile_x, tile_y) = (128 % 20, 128 / 20);
+        for x in 0..8 {
+            framebuffer[tile_y * 8 * 160 + tile_x * 8 + x] = DmgColor::Black;
+        }
+        sgb.handle_command(&command(&[0x14 << 3 | 1]), &framebuffer);
+        assert_eq!(sgb.border_palettes[0][0], 0xFFFF);
+        // tile 0 everywhere with border palette 5
+        for entry in sgb.border_map.iter_mut() {
+            *entry = 1 << 10;
+        }
+        sgb.border_palettes[1][1] = 0x1234;
+        let backdrop = sgb.palettes[0][0];
+        let screen = sgb.render(&framebuffer);
+        assert_eq!(screen[0], 0x1234);
+        assert_eq!(screen[SGB_WIDTH], backdrop);
+    }
+
+    #[test]
+    fn mask_test() {
+        let mut sgb = Sgb::new();
+        let framebuffer = vec![DmgColor::Black; 160 * 144];
+        sgb.handle_command(&command(&[0x17 << 3 | 1, 1]), &framebuffer);
+        let changed = vec![DmgColor::White; 160 * 144];
+        let inside = SCREEN_Y * SGB_WIDTH + SCREEN_X;
+        assert_eq!(sgb.render(&changed)[inside], DEFAULT_PALETTE[3]);
+        sgb.handle_command(&command(&[0x17 << 3 | 1, 2]), &framebuffer);
+        assert_eq!(sgb.render(&changed)[inside], 0);
+        sgb.handle_command(&command(&[0x17 << 3 | 1, 0]), &framebuffer);
+        assert_eq!(sgb.render(&changed)[inside], DEFAULT_PALETTE[0]);
+    }
+}
@@ -1,60 +1,122 @@
 #[derive(Debug)]
 pub struct Timer {
+    // DIV is the upper byte of this counter, incremented every clock cycle
     div_internal: u16,
-    tima: u32,
-    tima_counter: u32,
+    tima: u8,
+    tima_reload: TimaReload,
 
-    pub tma: u8,
-    pub tac: TimerControl,
+    tma: u8,
+    tac: TimerControl,
 }
 
-const CLOCK_FREQ_HZ: u32 = 4_194_304;
+// After TIMA overflows it reads 0 for one machine cycle, and only then gets
+// reloaded with TMA and the interrupt is requested.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum TimaReload {
+    None,
+    // TIMA overflowed, writing TIMA now cancels the reload
+    Pending,
+    // TIMA was just reloaded, writes to TIMA are ignored and writes to TMA go to TIMA too
+    Done,
+}
 
 impl Timer {
     pub fn div(&self) -> u8 { (self.div_internal >> 8) as u8 }
+    pub fn set_div_internal(&mut self, val: u16) {
+        self.div_internal = val;
+    }
     pub fn reset_div(&mut self) {
+        let old_signal = self.timer_signal();
         self.div_internal = 0;
-        self.tima_counter = 0;
+        self.on_signal_change(old_signal);
+    }
+
+    pub fn tima(&self) -> u8 { self.tima }
+    pub fn set_tima(&mut self, val: u8) {
+        match self.tima_reload {
+            TimaReload::None => self.tima = val,
+            TimaReload::Pending => {
+                self.tima = val;
+                self.tima_reload = TimaReload::None;
+            }
+            TimaReload::Done => {}
+        }
+    }
+
+    pub fn tma(&self) -> u8 { self.tma }
+    pub fn set_tma(&mut self, val: u8) {
+        self.tma = val;
+        if self.tima_reload == TimaReload::Done {
+            self.tima = val;
+        }
+    }
+
+    pub fn tac(&self) -> u8 { 0b1111_1000 | self.tac.to_u8() }
+    pub fn set_tac(&mut self, val: u8) {
+        let old_signal = self.timer_signal();
+        self.tac = TimerControl::from_u8(val);
+        self.on_signal_change(old_signal);
     }
-    pub fn tima(&self) -> u8 { self.tima as u8 }
-    pub fn set_tima(&mut self, val: u8) { self.tima = val as u32 }
 
-    // TODO check proper init values
     pub fn new() -> Timer {
         Timer {
             div_internal: 0,
             tima: 0,
-            tima_counter: 0,
+            tima_reload: TimaReload::None,
 
             tma: 0,
-            tac: TimerControl {
-                enabled: true,
-                clock_freq: TacFrequency::Hz4096,
-            },
+            tac: TimerControl::from_u8(0),
         }
     }
 
+    /// Takes time in machine cycles, returns true if the timer interrupt was requested
     pub fn pass_time(&mut self, cycles: u32) -> bool {
-        assert!(cycles < 256, "Loses precision");
-        const TIMA_CYCLES_NEEDED: u32 = 1024 / 4;
-
-        if self.tac.enabled {
-            let timaFreq = self.tac.clock_freq.get_frequency_hz();
-            let tima_multiplier = timaFreq / 4096;
-            self.tima_counter += tima_multiplier * cycles;
-
-            self.div_internal = self.div_internal.wrapping_add(4);
-            if self.tima_counter >= TIMA_CYCLES_NEEDED {
-                self.tima_counter -= TIMA_CYCLES_NEEDED;
-                self.tima += 1;
-            }
+        let mut interrupt = false;
+        for _ in 0..cycles {
+            interrupt |= self.tick();
+        }
+        interrupt
+    }
 
-            let tima_overflown = self.tima > 0xFF;
-            if tima_overflown {
-                self.tima = self.tma as u32;
+    fn tick(&mut self) -> bool {
+        let interrupt = match self.tima_reload {
+            TimaReload::Pending => {
+                self.tima = self.tma;
+                self.tima_reload = TimaReload::Done;
+                true
             }
-            tima_overflown
-        } else { false }
+            TimaReload::Done => {
+                self.tima_reload = TimaReload::None;
+                false
+            }
+            TimaReload::None => false,
+        };
+
+        let old_signal = self.timer_signal();
+        self.div_internal = self.div_internal.wrapping_add(4);
+        self.on_signal_change(old_signal);
+        interrupt
+    }
+
+    // TIMA is incremented on the falling edge of the selected DIV bit ANDed with
+    // the enable flag, so resetting DIV or writing TAC can increment it as well.
+    fn timer_signal(&self) -> bool {
+        let bit = self.tac.clock_freq.div_bit();
+        self.tac.enabled && self.div_internal & (1 << bit) != 0
+    }
+
+    fn on_signal_change(&mut self, old_signal: bool) {
+        if old_signal && !self.timer_signal() {
+            self.increment_tima();
+        }
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflown) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflown {
+            self.tima_reload = TimaReload::Pending;
+        }
     }
 }
 
@@ -94,12 +156,13 @@ enum TacFrequency {
 }
 
 impl TacFrequency {
-    fn get_frequency_hz(&self) -> u32 {
-        match *self {
-            TacFrequency::Hz4096 => 4096,       // every 1024 clock_cycles
-            TacFrequency::Hz262144 => 262144,   // every 16 clock cycles
-            TacFrequency::Hz65536 => 65536,     // every 64 clock cycles
-            TacFrequency::Hz16384 => 16384,     // every 256 clock cycles
+    // bit of the internal DIV counter that clocks TIMA
+    fn div_bit(self) -> u16 {
+        match self {
+            TacFrequency::Hz4096 => 9,      // every 1024 clock cycles
+            TacFrequency::Hz262144 => 3,    // every 16 clock cycles
+            TacFrequency::Hz65536 => 5,     // every 64 clock cycles
+            TacFrequency::Hz16384 => 7,     // every 256 clock cycles
         }
     }
 }
@@ -139,3 +202,70 @@ fn from_u8_test() {
         }
     );
 }
+
+#[test]
+fn div_runs_when_timer_disabled_test() {
+    let mut timer = Timer::new();
+    timer.pass_time(64);
+    assert_eq!(timer.div(), 1);
+    assert_eq!(timer.tima(), 0);
+}
+
+#[test]
+fn tima_frequency_test() {
+    let mut timer = Timer::new();
+    timer.set_tac(0b101); // every 16 clock cycles
+    timer.pass_time(3);
+    assert_eq!(timer.tima(), 0);
+    timer.pass_time(1);
+    assert_eq!(timer.tima(), 1);
+    timer.pass_time(40);
+    assert_eq!(timer.tima(), 11);
+}
+
+#[test]
+fn tima_overflow_delay_test() {
+    let mut timer = Timer::new();
+    timer.set_tma(0x80);
+    timer.set_tima(0xFF);
+    timer.set_tac(0b101);
+    assert!(!timer.pass_time(4));
+    assert_eq!(timer.tima(), 0x00); // reads 0 for one cycle
+    assert!(timer.pass_time(1));
+    assert_eq!(timer.tima(), 0x80);
+
+    timer.set_tima(0x10); // ignored in the cycle TMA is loaded
+    timer.set_tma(0x90); // but a new TMA is copied to TIMA
+    assert_eq!(timer.tima(), 0x90);
+    timer.pass_time(1);
+    timer.set_tima(0x10);
+    assert_eq!(timer.tima(), 0x10);
+}
+
+#[test]
+fn tima_write_cancels_reload_test() {
+    let mut timer = Timer::new();
+    timer.set_tima(0xFF);
+    timer.set_tac(0b101);
+    timer.pass_time(4);
+    timer.set_tima(0x42);
+    assert!(!timer.pass_time(1));
+    assert_eq!(timer.tima(), 0x42);
+}
+
+#[test]
+fn falling_edge_glitches_test() {
+    let mut timer = Timer::new();
+    timer.set_tac(0b101);
+    timer.pass_time(2); // bit 3 of DIV counter is set
+    timer.reset_div();
+    assert_eq!(timer.tima(), 1);
+
+    timer.pass_time(2);
+    timer.set_tac(0b001); // disabling the timer is a falling edge too
+    assert_eq!(timer.tima(), 2);
+
+    timer.set_tac(0b101);
+    timer.set_tac(0b100); // bit 9 is not set
+    assert_eq!(timer.tima(), 3);
+}
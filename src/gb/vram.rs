@@ -1,5 +1,5 @@
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DmgColor {
     Black,
     DarkGray,
@@ -7,6 +7,18 @@ pub enum DmgColor {
     White,
 }
 
+impl DmgColor {
+    /// 0 for white up to 3 for black
+    pub fn shade(self) -> u8 {
+        match self {
+            DmgColor::White => 0,
+            DmgColor::LightGray => 1,
+            DmgColor::DarkGray => 2,
+            DmgColor::Black => 3,
+        }
+    }
+}
+
 impl Default for DmgColor {
     fn default() -> Self {
         DmgColor::White
@@ -86,7 +98,8 @@ impl Oam {
     pub fn from_bytes(bytes: &[u8]) -> Oam {
         let mut oam: Oam = Default::default();
         for i in 0..40 {
-            oam.sprites[i] = OamEntry::from_bytes(&bytes[i*4..(i*4)+4])
+            oam.sprites[i] = OamEntry::from_bytes(&bytes[i*4..(i*4)+4]);
+            oam.sprites[i].oam_idx = i as u8;
         }
         oam
     }
@@ -113,6 +126,12 @@ pub struct OamEntry {
     // 0 or 1 from existing pallettes
     pub palette1: bool,
 
+    // CGB only: tile data from VRAM bank 1, palette 0-7
+    pub vram_bank1: bool,
+    pub cgb_palette: u8,
+
+    // position in OAM, sprite priority on CGB
+    pub oam_idx: u8,
 }
 
 impl OamEntry {
@@ -126,6 +145,9 @@ impl OamEntry {
             flip_y: (flags & (1 << 6)) != 0,
             flip_x: (flags & (1 << 5)) != 0,
             palette1: (flags & (1 << 4)) != 0,
+            vram_bank1: (flags & (1 << 3)) != 0,
+            cgb_palette: flags & 0b111,
+            oam_idx: 0,
         }
     }
 }
@@ -3,65 +3,301 @@
 
 #[macro_use]
 extern crate bitflags;
+extern crate flate2;
 extern crate image;
 extern crate piston_window;
+extern crate zip;
 
 use std::fs::File;
 use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
 
 use piston_window::*;
 
 use crate::emu::Emu;
+use crate::error::EmuError;
 use crate::frontend::*;
+use crate::frontend::blend::FrameBlend;
+use crate::frontend::filters::ScaleFilter;
+use crate::frontend::palette;
+use crate::frontend::palette::DmgPalettes;
 use crate::gb::*;
 use crate::gb::cpu::*;
 use crate::gb::Interrupts;
 use crate::gb::joypad::Joypad;
 use crate::gb::joypad::JoypadInterrupt;
+use crate::gb::cheats::Cheats;
+use crate::gb::compat_palettes;
+use crate::gb::compat_palettes::ManualPalette;
 use crate::gb::mbc::*;
+use crate::gb::model::*;
 use crate::gb::mmu::Mmu;
 use crate::gb::ppu::*;
+use crate::gb::sgb::Sgb;
+use crate::gb::sgb::SGB_HEIGHT;
+use crate::gb::sgb::SGB_WIDTH;
 use crate::gb::timer::Timer;
 
 mod frontend;
 mod emu;
+mod error;
+mod archive;
 mod gb;
+mod patch;
 mod util;
 
-fn load_rom(filename: &str) -> std::io::Result<Vec<u8>> {
-    let mut f: File = File::open(filename)?;
-    let size = f.metadata()?.len();
-    let mut contents = Vec::with_capacity(size as usize);
-    f.read_to_end(&mut contents)?;
-    Result::Ok(contents)
+struct Options {
+    rom_filename: String,
+    patch_filename: Option<String>,
+    // ROM to pick from a zip archive
+    archive_entry: Option<String>,
+    // report invalid opcodes as an error instead of just locking up the CPU
+    stop_on_invalid_opcode: bool,
+    // without a bootrom, emulation starts in the post-boot state of the model
+    bootrom_filename: Option<String>,
+    // picked from the cartridge header unless given
+    model: Option<Model>,
+    renderer: Renderer,
+    // colors for a DMG game on CGB, instead of the ones the boot ROM would pick
+    compat_palette: Option<ManualPalette>,
+    color_correction: ColorCorrection,
+    // DMG shades, the OBJ palettes follow the BG one unless given
+    dmg_palettes: DmgPalettes,
+    // weight of the previous frames when blending them with the current one
+    frame_blend: Option<f32>,
+    // window size as a multiple of the screen, the last size is kept if not given
+    scale: Option<u32>,
+    scale_filter: ScaleFilter,
 }
 
-fn build_cart(rom: Vec<u8>) -> Box<Cartridge> {
+const USAGE: &str = concat!(
+    "Usage: gb-rust [--bootrom <bootrom_file>] [--model dmg0|dmg|mgb|sgb|cgb] [--renderer scanline|fifo]\n",
+    "       [--compat-palette <direction>[+a|+b]] [--color-correction none|lcd]\n",
+    "       [--palette <preset>|<hex,hex,hex,hex>] [--obj0-palette <...>] [--obj1-palette <...>]\n",
+    "       [--frame-blend <0.0-0.95>] [--scale 1-8] [--filter none|scale2x|scale3x|hq2x|2xbr|lcd]\n",
+    "       [--patch <patch_file>] [--entry <name_in_archive>] [--stop-on-invalid-opcode] <rom_file>",
+);
+
+impl Options {
+    fn from_args(args: &[String]) -> Result<Options, String> {
+        let mut rom_filename = None;
+        let mut patch_filename = None;
+        let mut archive_entry = None;
+        let mut stop_on_invalid_opcode = false;
+        let mut bootrom_filename = None;
+        let mut model = None;
+        let mut renderer = Renderer::default();
+        let mut compat_palette = None;
+        let mut color_correction = ColorCorrection::default();
+        let mut bg_colors = None;
+        let mut obj0_colors = None;
+        let mut obj1_colors = None;
+        let mut frame_blend = None;
+        let mut scale = None;
+        let mut scale_filter = ScaleFilter::default();
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--patch" => patch_filename = args.next().cloned(),
+                "--entry" => archive_entry = args.next().cloned(),
+                "--stop-on-invalid-opcode" => stop_on_invalid_opcode = true,
+                "--bootrom" => bootrom_filename = args.next().cloned(),
+                "--model" => {
+                    let name = args.next().map(String::as_str).unwrap_or("");
+                    model = Some(Model::from_name(name).ok_or(format!("unknown model '{}'", name))?);
+                }
+                "--renderer" => {
+                    let name = args.next().map(String::as_str).unwrap_or("");
+                    renderer = Renderer::from_name(name).ok_or(format!("unknown renderer '{}'", name))?;
+                }
+                "--compat-palette" => {
+                    let name = args.next().map(String::as_str).unwrap_or("");
+                    compat_palette = Some(ManualPalette::from_name(name).ok_or(format!("unknown compat palette '{}'", name))?);
+                }
+                "--color-correction" => {
+                    let name = args.next().map(String::as_str).unwrap_or("");
+                    color_correction = ColorCorrection::from_name(name)
+                        .ok_or(format!("unknown color correction '{}'", name))?;
+                }
+                "--palette" | "--obj0-palette" | "--obj1-palette" => {
+                    let name = args.next().map(String::as_str).unwrap_or("");
+                    let colors = palette::shade_colors_from_name(name)
+                        .ok_or(format!("{} must be a preset or 4 hex colors, got '{}'", arg, name))?;
+                    let colors = Some(colors);
+                    match arg.as_str() {
+                        "--palette" => bg_colors = colors,
+                        "--obj0-palette" => obj0_colors = colors,
+                        _ => obj1_colors = colors,
+                    }
+                }
+                "--frame-blend" => {
+                    let weight = args.next().and_then(|w| w.parse::<f32>().ok());
+                    let weight = weight.filter(|w| (0.0..=0.95).contains(w));
+                    frame_blend = Some(weight.ok_or("--frame-blend must be between 0.0 and 0.95")?);
+                }
+                "--scale" => {
+                    let factor = args.next().and_then(|s| s.parse::<u32>().ok());
+                    scale = Some(factor.filter(|s| (1..=8).contains(s)).ok_or("--scale must be between 1 and 8")?);
+                }
+                "--filter" => {
+                    let name = args.next().map(String::as_str).unwrap_or("");
+                    scale_filter = ScaleFilter::from_name(name).ok_or(format!("unknown filter '{}'", name))?;
+                }
+                _ => rom_filename = Some(arg.clone()),
+            }
+        }
+        let bg = bg_colors.unwrap_or(DmgPalettes::default().bg);
+        let dmg_palettes = DmgPalettes { bg, obj0: obj0_colors.unwrap_or(bg), obj1: obj1_colors.unwrap_or(bg) };
+        Ok(Options {
+            rom_filename: rom_filename.ok_or("no ROM file given")?,
+            patch_filename,
+            archive_entry,
+            stop_on_invalid_opcode,
+            bootrom_filename,
+            model,
+            renderer,
+            compat_palette,
+            color_correction,
+            dmg_palettes,
+            frame_blend,
+            scale,
+            scale_filter,
+        })
+    }
+}
+
+fn read_file(filename: &str) -> Result<Vec<u8>, EmuError> {
+    let read = || -> std::io::Result<Vec<u8>> {
+        let mut f: File = File::open(filename)?;
+        let size = f.metadata()?.len();
+        let mut contents = Vec::with_capacity(size as usize);
+        f.read_to_end(&mut contents)?;
+        Result::Ok(contents)
+    };
+    read().map_err(|source| EmuError::Io { path: filename.to_string(), source })
+}
+
+/// ROMs may be stored in zip or gzip archives
+fn load_rom(filename: &str, archive_entry: Option<&str>) -> Result<Vec<u8>, EmuError> {
+    let contents = read_file(filename)?;
+    archive::extract_rom(contents, archive_entry)
+        .map_err(|source| EmuError::Io { path: filename.to_string(), source })
+}
+
+/// Applies the patch given explicitly or found next to the ROM, if there is any
+fn patch_rom(rom: Vec<u8>, options: &Options) -> Result<Vec<u8>, EmuError> {
+    let patch_filename = match options.patch_filename {
+        Some(ref f) => Some(PathBuf::from(f)),
+        None => patch::find_patch(&options.rom_filename),
+    };
+    match patch_filename {
+        Some(patch_filename) => {
+            let path = patch_filename.to_string_lossy().to_string();
+            let patch = read_file(&path)?;
+            patch::apply_patch(&rom, &patch).map_err(|source| EmuError::Patch { path, source })
+        }
+        None => Ok(rom),
+    }
+}
+
+fn build_cart(rom: Vec<u8>) -> Result<Box<Cartridge>, EmuError> {
+    const HEADER_END: usize = 0x150;
+    if rom.len() < HEADER_END {
+        return Err(EmuError::RomTooSmall(rom.len()));
+    }
     let mbc_type = rom[0x0147];
     match mbc_type {
-        0 => Box::new(NoMbc::new(rom)),
-        1 => Box::new(Mbc1::new(rom)),
-        _ => panic!("Unsupported MBC"),
+        0 => Ok(Box::new(NoMbc::new(rom))),
+        1 => Ok(Box::new(Mbc1::new(rom))),
+        _ => Err(EmuError::UnsupportedMbc(mbc_type)),
+    }
+}
+
+/// Cheats are read from a file next to the ROM, with the `.cht` extension
+fn load_cheats(rom_filename: &str) -> Cheats {
+    let path = Path::new(rom_filename).with_extension("cht");
+    if !path.exists() {
+        return Cheats::new();
+    }
+    match Cheats::from_file(&path) {
+        Ok(cheats) => cheats,
+        Err(e) => {
+            eprintln!("Error when loading cheats from {}: {}", path.display(), e);
+            Cheats::new()
+        }
     }
 }
 
 fn main() {
-    let skip_bootrom = false;
     let args: Vec<String> = std::env::args().collect();
-    let filename = &args[1];
-    let bootrom = load_rom("roms/bootrom.gb").expect("error when loading a ROM");
-    let rom = load_rom(filename).expect("error when loading a ROM");
-    let cart = build_cart(rom);
+    let options = match Options::from_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("Error: {}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(options: &Options) -> Result<(), EmuError> {
+    let filename = &options.rom_filename;
+    let bootrom = match options.bootrom_filename {
+        Some(ref f) => Some(read_file(f)?),
+        None => None,
+    };
+    let rom = load_rom(filename, options.archive_entry.as_deref())?;
+    let rom = patch_rom(rom, options)?;
+    let cart = build_cart(rom)?;
     let rom_name = cart.get_name();
-    let joypad = Joypad::new();
+    let cgb_cart = cart.supports_cgb();
+    let model = options.model.unwrap_or(if cgb_cart { Model::Cgb } else { Model::default() });
+    // CGB features are only turned on for games that use them
+    let cgb_mode = model.is_cgb() && cgb_cart;
+    // SGB commands are only listened to from games that are flagged for it
+    let sgb = model == Model::Sgb && cart.supports_sgb();
+    let mut joypad = Joypad::new();
+    if sgb {
+        joypad.enable_sgb();
+    }
     let timer = Timer::new();
-    let ppu = Ppu::new();
-    let mmu = Mmu::new(bootrom, cart, joypad, timer, ppu);
-    let cpu = Cpu::new(mmu);
-    let gb = Gb::new(cpu);
-    let frontend = Box::new(GlutinFrontend::new());
+    let mut ppu = Ppu::new();
+    ppu.renderer = options.renderer;
+    ppu.cgb_mode = cgb_mode;
+    // without a boot ROM the colors it would give a DMG game are loaded directly
+    ppu.dmg_compat_mode = model.is_cgb() && !cgb_cart && bootrom.is_none();
+    if ppu.dmg_compat_mode {
+        let palettes = match options.compat_palette {
+            Some(manual) => manual.palettes(),
+            None => compat_palettes::for_cartridge(&*cart),
+        };
+        palettes.load(&mut ppu);
+    }
+    let skip_bootrom = bootrom.is_none();
+    let mut mmu = Mmu::new(bootrom, cart, joypad, timer, ppu);
+    mmu.cheats = load_cheats(filename);
+    mmu.cgb_mode = cgb_mode;
+    if sgb {
+        mmu.sgb = Some(Sgb::new());
+    }
+    let mut cpu = Cpu::new(mmu);
+    if skip_bootrom {
+        init_post_boot_state(&mut cpu, model);
+    }
+    let mut gb = Gb::new(cpu);
+    gb.stop_on_invalid_opcode = options.stop_on_invalid_opcode;
+    let screen_dim = if sgb { [SGB_WIDTH as u32, SGB_HEIGHT as u32] } else { [160, 144] };
+    let mut frontend = Box::new(GlutinFrontend::new(screen_dim, options.scale));
+    frontend.color_correction = options.color_correction;
+    frontend.dmg_palettes = options.dmg_palettes;
+    frontend.frame_blend = options.frame_blend.map(FrameBlend::new);
+    frontend.set_scale_filter(options.scale_filter);
 
     let mut emu = Emu { gb, frontend };
 
-    emu.run_loop(skip_bootrom);
+    emu.run_loop()
 }
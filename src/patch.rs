@@ -0,0 +1,405 @@
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+// the biggest Game Boy cartridges have 8MB of ROM
+const MAX_TARGET_SIZE: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated,
+    // an offset, size or number that overflows or points outside of the data
+    OutOfRange,
+    SourceSizeMismatch { expected: usize, actual: usize },
+    SourceChecksumMismatch { expected: u32, actual: u32 },
+    TargetChecksumMismatch { expected: u32, actual: u32 },
+    PatchChecksumMismatch { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::UnknownFormat => write!(f, "unknown patch format"),
+            PatchError::Truncated => write!(f, "patch file is truncated"),
+            PatchError::OutOfRange => write!(f, "patch refers to data out of range"),
+            PatchError::SourceSizeMismatch { expected, actual } => write!(
+                f, "patch expects a ROM of {} bytes, got {} bytes", expected, actual
+            ),
+            PatchError::SourceChecksumMismatch { expected, actual } => write!(
+                f, "patch was made for a different ROM (CRC32 {:08X}, got {:08X})", expected, actual
+            ),
+            PatchError::TargetChecksumMismatch { expected, actual } => write!(
+                f, "patched ROM is corrupted (CRC32 {:08X}, expected {:08X})", actual, expected
+            ),
+            PatchError::PatchChecksumMismatch { expected, actual } => write!(
+                f, "patch file is corrupted (CRC32 {:08X}, expected {:08X})", actual, expected
+            ),
+        }
+    }
+}
+
+/// Looks for `.ips`, `.ups` or `.bps` file with the same name as the ROM
+pub fn find_patch(rom_filename: &str) -> Option<PathBuf> {
+    PATCH_EXTENSIONS.iter()
+        .map(|ext| Path::new(rom_filename).with_extension(ext))
+        .find(|path| path.exists())
+}
+
+/// Detects the patch format by its magic bytes and applies it to the ROM
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> PatchReader<'a> {
+        PatchReader { data, pos }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], PatchError> {
+        let bytes = self.data.get(self.pos..self.pos + n).ok_or(PatchError::Truncated)?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be_number(&mut self, n: usize) -> Result<usize, PatchError> {
+        let bytes = self.bytes(n)?;
+        Ok(bytes.iter().fold(0, |acc, &b| acc << 8 | b as usize))
+    }
+
+    fn le_u32(&mut self) -> Result<u32, PatchError> {
+        let bytes = self.bytes(4)?;
+        Ok(bytes.iter().rev().fold(0, |acc, &b| acc << 8 | b as u32))
+    }
+
+    // variable-length number used by both UPS and BPS
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut val: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x = self.byte()?;
+            let part = ((x & 0x7F) as usize).checked_mul(shift).ok_or(PatchError::OutOfRange)?;
+            val = val.checked_add(part).ok_or(PatchError::OutOfRange)?;
+            if x & 0x80 != 0 {
+                return Ok(val);
+            }
+            shift = shift.checked_mul(0x80).ok_or(PatchError::OutOfRange)?;
+            val = val.checked_add(shift).ok_or(PatchError::OutOfRange)?;
+        }
+    }
+}
+
+// "PATCH", then records: 3 byte offset, 2 byte size, data.
+// Size of 0 means RLE record: 2 byte count, 1 byte value.
+// "EOF" ends the records, optionally followed by 3 byte size to truncate the ROM to.
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = rom.to_vec();
+    let mut reader = PatchReader::new(patch, 5);
+    loop {
+        if reader.bytes(3)? == b"EOF" && reader.pos + 3 >= patch.len() {
+            break;
+        }
+        reader.pos -= 3;
+        let offset = reader.be_number(3)?;
+        let size = reader.be_number(2)?;
+        let (data, size) = if size == 0 {
+            let count = reader.be_number(2)?;
+            let val = reader.byte()?;
+            (vec![val; count], count)
+        } else {
+            (reader.bytes(size)?.to_vec(), size)
+        };
+        if out.len() < offset + size {
+            out.resize(offset + size, 0);
+        }
+        out[offset..offset + size].copy_from_slice(&data);
+    }
+    if reader.pos + 3 == patch.len() {
+        let truncate_to = reader.be_number(3)?;
+        out.truncate(truncate_to);
+    }
+    Ok(out)
+}
+
+// "UPS1", source size, target size, then hunks until the 12 byte footer:
+// relative offset, bytes XORed with the source terminated with 0.
+// Footer contains CRC32 of the source, the target and the patch itself.
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let footer_start = check_footer(rom, patch)?;
+    let mut reader = PatchReader::new(patch, 4);
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    if source_size != rom.len() {
+        return Err(PatchError::SourceSizeMismatch { expected: source_size, actual: rom.len() });
+    }
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::OutOfRange);
+    }
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+    let mut offset: usize = 0;
+    while reader.pos < footer_start {
+        offset = offset.checked_add(reader.varint()?).ok_or(PatchError::OutOfRange)?;
+        loop {
+            let x = reader.byte()?;
+            if x == 0 {
+                offset = offset.checked_add(1).ok_or(PatchError::OutOfRange)?;
+                break;
+            }
+            *out.get_mut(offset).ok_or(PatchError::Truncated)? ^= x;
+            offset += 1;
+        }
+    }
+    check_target(&out, patch)?;
+    Ok(out)
+}
+
+// "BPS1", source size, target size, metadata, then actions until the 12 byte footer.
+// Footer has the same layout as in UPS.
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    const SOURCE_READ: usize = 0;
+    const TARGET_READ: usize = 1;
+    const SOURCE_COPY: usize = 2;
+    const TARGET_COPY: usize = 3;
+
+    let footer_start = check_footer(rom, patch)?;
+    let mut reader = PatchReader::new(patch, 4);
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(PatchError::SourceSizeMismatch { expected: source_size, actual: rom.len() });
+    }
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::OutOfRange);
+    }
+
+    let mut out = vec![];
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while reader.pos < footer_start {
+        let data = reader.varint()?;
+        let length = (data >> 2) + 1;
+        // no action may write past the target size
+        if length > target_size - out.len() {
+            return Err(PatchError::OutOfRange);
+        }
+        match data & 0b11 {
+            SOURCE_READ => {
+                let from = out.len();
+                let bytes = rom.get(from..from + length).ok_or(PatchError::OutOfRange)?;
+                out.extend_from_slice(bytes);
+            }
+            TARGET_READ => out.extend_from_slice(reader.bytes(length)?),
+            SOURCE_COPY => {
+                source_offset = add_signed_offset(source_offset, reader.varint()?)?;
+                let end = source_offset.checked_add(length).ok_or(PatchError::OutOfRange)?;
+                let bytes = rom.get(source_offset..end).ok_or(PatchError::OutOfRange)?;
+                out.extend_from_slice(bytes);
+                source_offset = end;
+            }
+            TARGET_COPY => {
+                target_offset = add_signed_offset(target_offset, reader.varint()?)?;
+                // source and destination may overlap, so copy byte by byte
+                for _ in 0..length {
+                    let b = *out.get(target_offset).ok_or(PatchError::OutOfRange)?;
+                    out.push(b);
+                    target_offset += 1;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+    if out.len() != target_size {
+        return Err(PatchError::Truncated);
+    }
+    check_target(&out, patch)?;
+    Ok(out)
+}
+
+/// BPS relative offsets keep the sign in the lowest bit, offsets before the start are invalid
+fn add_signed_offset(offset: usize, n: usize) -> Result<usize, PatchError> {
+    let abs = n >> 1;
+    let result = if n & 1 != 0 { offset.checked_sub(abs) } else { offset.checked_add(abs) };
+    result.ok_or(PatchError::OutOfRange)
+}
+
+/// Validates patch and source checksums, returns the offset of the footer
+fn check_footer(rom: &[u8], patch: &[u8]) -> Result<usize, PatchError> {
+    if patch.len() < 4 + 12 {
+        return Err(PatchError::Truncated);
+    }
+    let footer_start = patch.len() - 12;
+    let mut footer = PatchReader::new(patch, footer_start);
+    let source_crc = footer.le_u32()?;
+    footer.le_u32()?;
+    let patch_crc = footer.le_u32()?;
+
+    let actual = crc32(&patch[..patch.len() - 4]);
+    if actual != patch_crc {
+        return Err(PatchError::PatchChecksumMismatch { expected: patch_crc, actual });
+    }
+    let actual = crc32(rom);
+    if actual != source_crc {
+        return Err(PatchError::SourceChecksumMismatch { expected: source_crc, actual });
+    }
+    Ok(footer_start)
+}
+
+fn check_target(out: &[u8], patch: &[u8]) -> Result<(), PatchError> {
+    let target_crc = PatchReader::new(patch, patch.len() - 8).le_u32()?;
+    let actual = crc32(out);
+    if actual != target_crc {
+        return Err(PatchError::TargetChecksumMismatch { expected: target_crc, actual });
+    }
+    Ok(())
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    const POLY: u32 = 0xEDB8_8320;
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let patch_crc = crc32(&patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+        patch
+    }
+
+    // encodes a number the way PatchReader::varint reads it
+    fn varint(mut n: usize) -> Vec<u8> {
+        let mut bytes = vec![];
+        loop {
+            let x = (n & 0x7F) as u8;
+            n >>= 7;
+            if n == 0 {
+                bytes.push(0x80 | x);
+                return bytes;
+            }
+            bytes.push(x);
+            n -= 1;
+        }
+    }
+
+    #[test]
+    fn crc32_test() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn ips_test() {
+        let rom = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 4, 0xCC]); // RLE, grows the ROM
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(
+            apply_patch(&rom, &patch),
+            Ok(vec![0, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC, 0xCC, 0xCC])
+        );
+
+        patch.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(apply_patch(&rom, &patch), Ok(vec![0, 0xAA, 0xBB]));
+    }
+
+    #[test]
+    fn ups_test() {
+        let source = [1u8, 2, 3, 4];
+        let target = [1u8, 7, 3, 4, 5];
+        let mut patch = b"UPS1".to_vec();
+        patch.extend_from_slice(&[0x84, 0x85]); // sizes
+        patch.extend_from_slice(&[0x81, 2 ^ 7, 0]); // skip 1, xor 1 byte
+        patch.extend_from_slice(&[0x81, 5, 0]); // skip 1, xor 1 byte
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply_patch(&source, &patch), Ok(target.to_vec()));
+
+        assert_eq!(
+            apply_patch(&target, &patch),
+            Err(PatchError::SourceChecksumMismatch { expected: crc32(&source), actual: crc32(&target) })
+        );
+    }
+
+    #[test]
+    fn bps_test() {
+        let source = [1u8, 2, 3, 4];
+        let target = [1u8, 2, 9, 9, 9, 3, 4];
+        let mut patch = b"BPS1".to_vec();
+        patch.extend_from_slice(&[0x84, 0x87, 0x80]); // sizes, no metadata
+        patch.push(0x80 | (1 << 2)); // source read 2 bytes
+        patch.extend_from_slice(&[0x80 | 1, 9]); // target read 1 byte
+        patch.extend_from_slice(&[0x80 | (1 << 2) | 3, 0x80 | 4]); // target copy 2 bytes from offset 2
+        patch.extend_from_slice(&[0x80 | (1 << 2) | 2, 0x80 | 4]); // source copy 2 bytes from offset 2
+        let mut patch = with_footer(patch, &source, &target);
+        assert_eq!(apply_patch(&source, &patch), Ok(target.to_vec()));
+
+        patch[6] ^= 0xFF;
+        match apply_patch(&source, &patch) {
+            Err(PatchError::PatchChecksumMismatch { .. }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn malformed_patch_test() {
+        let source = [1u8, 2, 3, 4];
+        let bps = |actions: &[u8]| {
+            let mut patch = b"BPS1".to_vec();
+            patch.extend_from_slice(&[0x84, 0x87, 0x80]);
+            patch.extend_from_slice(actions);
+            with_footer(patch, &source, &source)
+        };
+        // varint without an end would overflow
+        assert_eq!(apply_patch(&source, &bps(&[0x7F; 20])), Err(PatchError::OutOfRange));
+        // source copy from before the start of the ROM
+        assert_eq!(apply_patch(&source, &bps(&[0x80 | 2, 0x80 | 3])), Err(PatchError::OutOfRange));
+        // target copy longer than the target
+        assert_eq!(apply_patch(&source, &bps(&[0x80 | 1, 9, 0x7F, 0x80 | 3, 0x80])), Err(PatchError::OutOfRange));
+
+        // a huge target size isn't allocated
+        let mut patch = b"UPS1".to_vec();
+        patch.extend_from_slice(&[0x84, 0x7F, 0x7F, 0x7F, 0x7F, 0x80]);
+        let patch = with_footer(patch, &source, &source);
+        assert_eq!(apply_patch(&source, &patch), Err(PatchError::OutOfRange));
+
+        // a hunk at the largest offset, with only its terminating 0
+        let mut patch = b"UPS1".to_vec();
+        patch.extend_from_slice(&[0x84, 0x84]);
+        patch.extend_from_slice(&varint(usize::MAX));
+        patch.push(0);
+        let patch = with_footer(patch, &source, &source);
+        assert_eq!(apply_patch(&source, &patch), Err(PatchError::OutOfRange));
+    }
+}